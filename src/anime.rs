@@ -0,0 +1,236 @@
+use Title;
+use error::*;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+/// All titles for a single AniDB anime.
+#[derive(Debug, PartialEq)]
+pub struct AnimeEntry {
+    pub id: u32,
+    pub titles: Vec<Title>,
+}
+
+/// Groups titles by anime ID as they're read, relying on the dump being
+/// sorted by ID (which AniDB's dumps are). Only one anime's titles are held
+/// in memory at a time.
+///
+/// If an ID is seen out of order, iteration stops with an
+/// `ErrorKind::UnsortedTitles` error, in which case
+/// `group_by_anime_unsorted` can be used instead.
+pub fn group_by_anime<I>(titles: I) -> AnimeEntries<I::IntoIter>
+    where I: IntoIterator<Item = Result<Title>>
+{
+    AnimeEntries {
+        titles: titles.into_iter(),
+        pending: None,
+        last_id: None,
+        done: false,
+    }
+}
+
+/// Groups titles by anime ID, for input that isn't sorted by ID. Unlike
+/// `group_by_anime`, this reads all titles into memory first.
+pub fn group_by_anime_unsorted<I>(titles: I) -> Result<Vec<AnimeEntry>>
+    where I: IntoIterator<Item = Result<Title>>
+{
+    let mut titles_hash_map: HashMap<u32, Vec<Title>> = HashMap::new();
+
+    for title_result in titles {
+        let title = title_result?;
+
+        match titles_hash_map.entry(title.id) {
+            Entry::Occupied(mut o) => {
+                o.get_mut().push(title);
+            }
+            Entry::Vacant(v) => {
+                v.insert(vec![title]);
+            }
+        };
+    }
+
+    let mut entries = titles_hash_map.drain()
+        .map(|(id, titles)| {
+            AnimeEntry {
+                id: id,
+                titles: titles,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    entries.sort_by_key(|e| e.id);
+
+    Ok(entries)
+}
+
+pub struct AnimeEntries<I> {
+    titles: I,
+    // The item read past the end of the previous entry
+    pending: Option<Result<Title>>,
+    // ID of the previous entry, since an error in between means the next
+    // entry's first title was never compared with it
+    last_id: Option<u32>,
+    done: bool,
+}
+
+impl<I> Iterator for AnimeEntries<I>
+    where I: Iterator<Item = Result<Title>>
+{
+    type Item = Result<AnimeEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let first = match self.pending.take().or_else(|| self.titles.next()) {
+            Some(Ok(title)) => title,
+            Some(Err(e)) => return Some(Err(e)),
+            None => {
+                self.done = true;
+                return None;
+            }
+        };
+
+        let id = first.id;
+
+        if let Some(last_id) = self.last_id {
+            if id <= last_id {
+                self.done = true;
+                return Some(Err(ErrorKind::UnsortedTitles(id, last_id).into()));
+            }
+        }
+
+        self.last_id = Some(id);
+        let mut titles = vec![first];
+
+        loop {
+            match self.titles.next() {
+                Some(Ok(title)) => {
+                    if title.id == id {
+                        titles.push(title);
+                    } else if title.id < id {
+                        self.done = true;
+                        return Some(Err(ErrorKind::UnsortedTitles(title.id, id).into()));
+                    } else {
+                        self.pending = Some(Ok(title));
+                        break;
+                    }
+                }
+
+                // Return the error after this entry
+                Some(Err(e)) => {
+                    self.pending = Some(Err(e));
+                    break;
+                }
+
+                None => {
+                    self.done = true;
+                    break;
+                }
+            }
+        }
+
+        Some(Ok(AnimeEntry {
+            id: id,
+            titles: titles,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TitleType;
+
+    fn title(id: u32, title: &str) -> Result<Title> {
+        Ok(Title {
+            id: id,
+            title_type: TitleType::Primary,
+            language: "x-jat".to_string(),
+            title: title.to_string(),
+            raw_title: None,
+        })
+    }
+
+    fn bad_line(line_num: u32) -> Result<Title> {
+        Err(ErrorKind::InvalidLine(line_num, "bad".to_string()).into())
+    }
+
+    // Describes each entry by its ID and titles, and each error by its line
+    // number or IDs
+    fn summarize(entries: Vec<Result<AnimeEntry>>) -> Vec<String> {
+        entries.into_iter().map(|entry| describe(entry)).collect()
+    }
+
+    fn describe(entry: Result<AnimeEntry>) -> String {
+        let e = match entry {
+            Ok(entry) => {
+                let titles = entry.titles.iter().map(|t| t.title.as_str()).collect::<Vec<_>>();
+                return format!("{}: {}", entry.id, titles.join(", "));
+            }
+            Err(e) => e,
+        };
+
+        match *e.kind() {
+            ErrorKind::InvalidLine(line_num, _) => format!("bad line {}", line_num),
+            ErrorKind::UnsortedTitles(id, previous_id) => {
+                format!("unsorted {} after {}", id, previous_id)
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn groups_sorted_titles() {
+        let titles = vec![title(1, "a"), title(1, "b"), title(22, "c"), title(23, "d")];
+
+        assert_eq!(summarize(group_by_anime(titles).collect()),
+                   vec!["1: a, b", "22: c", "23: d"]);
+        assert!(group_by_anime(Vec::<Result<Title>>::new()).next().is_none());
+    }
+
+    #[test]
+    fn returns_errors_between_entries() {
+        let titles = vec![title(1, "a"), bad_line(5), title(2, "b"), title(2, "c")];
+
+        // The entry before the error is complete, so it comes first
+        assert_eq!(summarize(group_by_anime(titles).collect()),
+                   vec!["1: a", "bad line 5", "2: b, c"]);
+    }
+
+    #[test]
+    fn stops_at_unsorted_titles() {
+        let titles = vec![title(2, "a"), title(3, "b"), title(1, "c"), title(4, "d")];
+        assert_eq!(summarize(group_by_anime(titles).collect()),
+                   vec!["2: a", "unsorted 1 after 3"]);
+
+        // Also across a bad line
+        let titles = vec![title(2, "a"), title(3, "b"), bad_line(5), title(1, "c")];
+        assert_eq!(summarize(group_by_anime(titles).collect()),
+                   vec!["2: a", "3: b", "bad line 5", "unsorted 1 after 3"]);
+
+        // Anime 1 continuing after a bad line would otherwise be split in two
+        let titles = vec![title(1, "a"), bad_line(5), title(1, "b"), title(2, "c")];
+        assert_eq!(summarize(group_by_anime(titles).collect()),
+                   vec!["1: a", "bad line 5", "unsorted 1 after 1"]);
+    }
+
+    #[test]
+    fn groups_unsorted_titles() {
+        let titles = vec![title(22, "a"), title(1, "b"), title(22, "c"), title(5, "d")];
+        let entries = group_by_anime_unsorted(titles).unwrap();
+
+        assert_eq!(summarize(entries.into_iter().map(Ok).collect()),
+                   vec!["1: b", "5: d", "22: a, c"]);
+    }
+
+    #[test]
+    fn fails_unsorted_grouping_on_bad_lines() {
+        let titles = vec![title(22, "a"), bad_line(5), title(1, "b")];
+
+        match *group_by_anime_unsorted(titles).unwrap_err().kind() {
+            ErrorKind::InvalidLine(line_num, _) => assert_eq!(line_num, 5),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use error::*;
+use matcher::Candidate;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+/// A ClubDAM title accepted as a match, with the score of its best candidate.
+#[derive(Debug, Clone, Serialize)]
+pub struct ScoredTitle {
+    pub clubdam_title: String,
+    /// 0.0 for titles matched by an override, which aren't scored
+    pub score: f64,
+    /// Whether the match comes from an override rather than the search
+    pub overridden: bool,
+}
+
+/// Several ClubDAM titles matched to the same AniDB series. Sometimes these
+/// are just variants of the same title, but often one of them is a mismatch.
+#[derive(Debug, Clone, Serialize)]
+pub struct ManyToOne {
+    pub anidb_id: String,
+    /// Overridden titles first, then best score first
+    pub clubdam_titles: Vec<ScoredTitle>,
+}
+
+/// A ClubDAM title whose best candidates all have the same score, so the
+/// chosen one is effectively arbitrary.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tie {
+    pub clubdam_title: String,
+    pub score: f64,
+    pub anidb_ids: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Conflicts {
+    pub many_to_one: Vec<ManyToOne>,
+    pub ties: Vec<Tie>,
+}
+
+impl Conflicts {
+    /// Finds conflicts among accepted matches, given as ClubDAM titles with
+    /// their candidates (best first), and matches forced by overrides, given
+    /// as ClubDAM titles with AniDB IDs. Overrides are included since two of
+    /// them pointing at the same series is as likely to be a mistake as two
+    /// search results, but they can't tie.
+    pub fn find(matches: &[(String, Vec<Candidate>)], overridden: &[(String, String)]) -> Self {
+        let mut by_id: HashMap<&str, Vec<ScoredTitle>> = HashMap::new();
+        let mut ties = Vec::new();
+
+        for &(ref title, ref anidb_id) in overridden {
+            by_id.entry(anidb_id.as_str()).or_insert_with(Vec::new).push(ScoredTitle {
+                clubdam_title: title.clone(),
+                score: 0.0,
+                overridden: true,
+            });
+        }
+
+        for &(ref title, ref candidates) in matches {
+            let best = match candidates.first() {
+                Some(best) => best,
+                None => continue,
+            };
+
+            by_id.entry(best.series.id.as_str()).or_insert_with(Vec::new).push(ScoredTitle {
+                clubdam_title: title.clone(),
+                score: best.score,
+                overridden: false,
+            });
+
+            let tied = candidates.iter()
+                .take_while(|c| c.score == best.score)
+                .map(|c| c.series.id.clone())
+                .collect::<Vec<String>>();
+
+            if tied.len() > 1 {
+                ties.push(Tie {
+                    clubdam_title: title.clone(),
+                    score: best.score,
+                    anidb_ids: tied,
+                });
+            }
+        }
+
+        let mut many_to_one = by_id.into_iter()
+            .filter(|&(_, ref titles)| titles.len() > 1)
+            .map(|(id, mut titles)| {
+                titles.sort_by(|a, b| {
+                    b.overridden
+                        .cmp(&a.overridden)
+                        .then(b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal))
+                });
+                ManyToOne {
+                    anidb_id: id.to_string(),
+                    clubdam_titles: titles,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // Sorted for stable output
+        many_to_one.sort_by(|a, b| a.anidb_id.cmp(&b.anidb_id));
+
+        Conflicts {
+            many_to_one: many_to_one,
+            ties: ties,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.many_to_one.is_empty() && self.ties.is_empty()
+    }
+}
+
+/// What to do with conflicting matches.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ConflictPolicy {
+    /// Keep every match, only reporting the conflicts
+    KeepAll,
+    /// For each AniDB series, keep only the best-scoring ClubDAM title (or
+    /// only the overridden titles, if there are any). Ties keep the first
+    /// candidate.
+    KeepBest,
+    /// Reject all conflicting matches, leaving them for the `review`
+    /// subcommand
+    Defer,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::KeepAll
+    }
+}
+
+impl FromStr for ConflictPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep-all" => Ok(ConflictPolicy::KeepAll),
+            "keep-best" => Ok(ConflictPolicy::KeepBest),
+            "defer" => Ok(ConflictPolicy::Defer),
+            _ => Err(ErrorKind::InvalidConflictPolicy(s.to_string()).into()),
+        }
+    }
+}
+
+impl ConflictPolicy {
+    /// The ClubDAM titles whose matches should be rejected. Overridden titles
+    /// are never rejected, since an override is a deliberate decision.
+    pub fn rejected(&self, conflicts: &Conflicts) -> HashSet<String> {
+        let many_to_one = conflicts.many_to_one.iter();
+
+        let rejected = match *self {
+            ConflictPolicy::KeepAll => return HashSet::new(),
+            ConflictPolicy::KeepBest => {
+                many_to_one.flat_map(|m| m.clubdam_titles.iter().skip(1))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            }
+            ConflictPolicy::Defer => {
+                many_to_one.flat_map(|m| m.clubdam_titles.iter())
+                    .cloned()
+                    .chain(conflicts.ties.iter().map(|t| {
+                        ScoredTitle {
+                            clubdam_title: t.clubdam_title.clone(),
+                            score: t.score,
+                            overridden: false,
+                        }
+                    }))
+                    .collect::<Vec<_>>()
+            }
+        };
+
+        rejected.into_iter()
+            .filter(|t| !t.overridden)
+            .map(|t| t.clubdam_title)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elastic::{Series, TitlesByLanguage};
+
+    fn candidate(id: &str, score: f64) -> Candidate {
+        Candidate {
+            series: Series {
+                id: id.to_string(),
+                main_title: None,
+                titles: TitlesByLanguage(HashMap::new()),
+                normalized_titles: Vec::new(),
+            },
+            score: score,
+            matched_fields: Vec::new(),
+        }
+    }
+
+    fn titles(m: &ManyToOne) -> Vec<&str> {
+        m.clubdam_titles.iter().map(|t| t.clubdam_title.as_str()).collect()
+    }
+
+    #[test]
+    fn finds_many_to_one_and_ties() {
+        let matches = vec![("進撃の巨人".to_string(), vec![candidate("9541", 10.0)]),
+                           ("進撃の巨人 Season2".to_string(),
+                            vec![candidate("9541", 8.0), candidate("12345", 7.0)]),
+                           ("アイカツ".to_string(),
+                            vec![candidate("9355", 5.0), candidate("9356", 5.0)])];
+
+        let conflicts = Conflicts::find(&matches, &[]);
+
+        assert_eq!(conflicts.many_to_one.len(), 1);
+        assert_eq!(conflicts.many_to_one[0].anidb_id, "9541");
+        assert_eq!(titles(&conflicts.many_to_one[0]), vec!["進撃の巨人", "進撃の巨人 Season2"]);
+
+        assert_eq!(conflicts.ties.len(), 1);
+        assert_eq!(conflicts.ties[0].clubdam_title, "アイカツ");
+        assert_eq!(conflicts.ties[0].anidb_ids, vec!["9355", "9356"]);
+    }
+
+    #[test]
+    fn reports_overrides_pointing_at_the_same_series() {
+        let overridden = vec![("進撃の巨人".to_string(), "9541".to_string()),
+                              ("進撃の巨人 Season2".to_string(), "9541".to_string())];
+
+        let conflicts = Conflicts::find(&[], &overridden);
+
+        assert_eq!(conflicts.many_to_one.len(), 1);
+        assert_eq!(conflicts.many_to_one[0].anidb_id, "9541");
+        assert!(conflicts.many_to_one[0].clubdam_titles.iter().all(|t| t.overridden));
+
+        // Reported, but never rejected
+        assert!(ConflictPolicy::Defer.rejected(&conflicts).is_empty());
+    }
+
+    #[test]
+    fn policies_keep_overridden_titles() {
+        let matches = vec![("Shingeki no Kyojin".to_string(), vec![candidate("9541", 10.0)])];
+        let overridden = vec![("進撃の巨人".to_string(), "9541".to_string())];
+
+        let conflicts = Conflicts::find(&matches, &overridden);
+        assert_eq!(titles(&conflicts.many_to_one[0]), vec!["進撃の巨人", "Shingeki no Kyojin"]);
+
+        for policy in &[ConflictPolicy::KeepBest, ConflictPolicy::Defer] {
+            let rejected = policy.rejected(&conflicts);
+            assert_eq!(rejected.len(), 1);
+            assert!(rejected.contains("Shingeki no Kyojin"));
+        }
+
+        assert!(ConflictPolicy::KeepAll.rejected(&conflicts).is_empty());
+    }
+
+    #[test]
+    fn keep_best_rejects_lower_scores() {
+        let matches = vec![("a".to_string(), vec![candidate("1", 5.0)]),
+                           ("b".to_string(), vec![candidate("1", 9.0)]),
+                           ("c".to_string(), vec![candidate("1", 7.0)])];
+
+        let conflicts = Conflicts::find(&matches, &[]);
+        let mut rejected = ConflictPolicy::KeepBest.rejected(&conflicts)
+            .into_iter()
+            .collect::<Vec<_>>();
+        rejected.sort();
+
+        assert_eq!(rejected, vec!["a", "c"]);
+        assert_eq!("defer".parse::<ConflictPolicy>().unwrap(), ConflictPolicy::Defer);
+        assert!("keep-worst".parse::<ConflictPolicy>().is_err());
+    }
+}
@@ -0,0 +1,351 @@
+use {Title, TitleType};
+use anime::{AnimeEntry, group_by_anime};
+use error::*;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum DiffKind {
+    #[serde(rename = "added")]
+    Added,
+    #[serde(rename = "removed")]
+    Removed,
+    #[serde(rename = "changed")]
+    Changed,
+}
+
+impl DiffKind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            DiffKind::Added => "added",
+            DiffKind::Removed => "removed",
+            DiffKind::Changed => "changed",
+        }
+    }
+}
+
+/// Differences in a single anime's titles between two dumps.
+#[derive(Debug, Serialize)]
+pub struct AnimeDiff {
+    pub id: u32,
+    /// Whether the anime as a whole was added, removed, or just had some of
+    /// its titles changed
+    pub kind: DiffKind,
+    pub titles: Vec<TitleDiff>,
+}
+
+/// A title that was added, removed, or changed. A title counts as changed
+/// (rather than removed and re-added) if it's the only one of its language
+/// and type that differs between the dumps.
+#[derive(Debug, Serialize)]
+pub struct TitleDiff {
+    pub kind: DiffKind,
+    pub language: String,
+    pub title_type: TitleType,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compares two dumps, returning the anime whose titles differ, ordered by
+/// ID. Both dumps are expected to be sorted by ID, as with `group_by_anime`.
+pub fn diff<A, B>(old: A, new: B) -> Result<Vec<AnimeDiff>>
+    where A: IntoIterator<Item = Result<Title>>,
+          B: IntoIterator<Item = Result<Title>>
+{
+    let mut old_entries = group_by_anime(old);
+    let mut new_entries = group_by_anime(new);
+
+    let mut old_entry = next_entry(&mut old_entries)?;
+    let mut new_entry = next_entry(&mut new_entries)?;
+
+    let mut diffs = Vec::new();
+
+    loop {
+        match (old_entry.take(), new_entry.take()) {
+            (None, None) => break,
+            (Some(o), None) => {
+                diffs.push(AnimeDiff::whole(o, DiffKind::Removed));
+                old_entry = next_entry(&mut old_entries)?;
+            }
+            (None, Some(n)) => {
+                diffs.push(AnimeDiff::whole(n, DiffKind::Added));
+                new_entry = next_entry(&mut new_entries)?;
+            }
+            (Some(o), Some(n)) => {
+                if o.id < n.id {
+                    diffs.push(AnimeDiff::whole(o, DiffKind::Removed));
+                    old_entry = next_entry(&mut old_entries)?;
+                    new_entry = Some(n);
+                } else if o.id > n.id {
+                    diffs.push(AnimeDiff::whole(n, DiffKind::Added));
+                    old_entry = Some(o);
+                    new_entry = next_entry(&mut new_entries)?;
+                } else {
+                    let titles = diff_titles(o.titles, n.titles);
+
+                    if !titles.is_empty() {
+                        diffs.push(AnimeDiff {
+                            id: o.id,
+                            kind: DiffKind::Changed,
+                            titles: titles,
+                        });
+                    }
+
+                    old_entry = next_entry(&mut old_entries)?;
+                    new_entry = next_entry(&mut new_entries)?;
+                }
+            }
+        }
+    }
+
+    Ok(diffs)
+}
+
+impl AnimeDiff {
+    // For an anime that only exists in one of the dumps
+    fn whole(entry: AnimeEntry, kind: DiffKind) -> Self {
+        let titles = entry.titles
+            .into_iter()
+            .map(|title| {
+                let (old, new) = if kind == DiffKind::Removed {
+                    (Some(title.title), None)
+                } else {
+                    (None, Some(title.title))
+                };
+
+                TitleDiff {
+                    kind: kind,
+                    language: title.language,
+                    title_type: title.title_type,
+                    old: old,
+                    new: new,
+                }
+            })
+            .collect();
+
+        AnimeDiff {
+            id: entry.id,
+            kind: kind,
+            titles: titles,
+        }
+    }
+}
+
+fn next_entry<I>(entries: &mut I) -> Result<Option<AnimeEntry>>
+    where I: Iterator<Item = Result<AnimeEntry>>
+{
+    match entries.next() {
+        Some(Ok(entry)) => Ok(Some(entry)),
+        Some(Err(e)) => Err(e),
+        None => Ok(None),
+    }
+}
+
+type TitlesByKey = HashMap<(String, TitleType), Vec<String>>;
+
+fn by_key(titles: Vec<Title>) -> TitlesByKey {
+    let mut by_key: TitlesByKey = HashMap::new();
+
+    for title in titles {
+        match by_key.entry((title.language, title.title_type)) {
+            Entry::Occupied(mut o) => {
+                o.get_mut().push(title.title);
+            }
+            Entry::Vacant(v) => {
+                v.insert(vec![title.title]);
+            }
+        }
+    }
+
+    by_key
+}
+
+fn diff_titles(old: Vec<Title>, new: Vec<Title>) -> Vec<TitleDiff> {
+    let mut old_by_key = by_key(old);
+    let mut new_by_key = by_key(new);
+
+    let mut keys = old_by_key.keys().chain(new_by_key.keys()).cloned().collect::<Vec<_>>();
+    keys.sort_by(|a, b| (&a.0, a.1.name()).cmp(&(&b.0, b.1.name())));
+    keys.dedup();
+
+    let mut diffs = Vec::new();
+
+    for key in keys {
+        let old_titles = old_by_key.remove(&key).unwrap_or_else(Vec::new);
+        let mut added = new_by_key.remove(&key).unwrap_or_else(Vec::new);
+
+        let mut removed = Vec::new();
+        for title in old_titles {
+            match added.iter().position(|t| *t == title) {
+                Some(pos) => {
+                    added.remove(pos);
+                }
+                None => removed.push(title),
+            }
+        }
+
+        let (language, title_type) = key;
+
+        if removed.len() == 1 && added.len() == 1 {
+            diffs.push(TitleDiff {
+                kind: DiffKind::Changed,
+                language: language,
+                title_type: title_type,
+                old: removed.pop(),
+                new: added.pop(),
+            });
+            continue;
+        }
+
+        for title in removed {
+            diffs.push(TitleDiff {
+                kind: DiffKind::Removed,
+                language: language.clone(),
+                title_type: title_type.clone(),
+                old: Some(title),
+                new: None,
+            });
+        }
+
+        for title in added {
+            diffs.push(TitleDiff {
+                kind: DiffKind::Added,
+                language: language.clone(),
+                title_type: title_type.clone(),
+                old: None,
+                new: Some(title),
+            });
+        }
+    }
+
+    diffs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn title(id: u32, title_type: TitleType, language: &str, title: &str) -> Result<Title> {
+        Ok(Title {
+            id: id,
+            title_type: title_type,
+            language: language.to_string(),
+            title: title.to_string(),
+            raw_title: None,
+        })
+    }
+
+    fn main_title(id: u32, text: &str) -> Result<Title> {
+        title(id, TitleType::Primary, "x-jat", text)
+    }
+
+    // Each title diff as "kind language type: old -> new"
+    fn describe(titles: &[TitleDiff]) -> Vec<String> {
+        titles.iter()
+            .map(|t| {
+                format!("{} {} {}: {} -> {}",
+                        t.kind.name(),
+                        t.language,
+                        t.title_type.name(),
+                        t.old.as_ref().map_or("-", |s| s.as_str()),
+                        t.new.as_ref().map_or("-", |s| s.as_str()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn finds_added_removed_and_changed_anime() {
+        let old = vec![main_title(1, "Seikai no Monshou"),
+                       main_title(2, "Shinseiki Evangelion"),
+                       main_title(4, "Cowboy Bebop"),
+                       main_title(5, "Trigun")];
+        let new = vec![main_title(2, "Shin Seiki Evangelion"),
+                       main_title(3, "Seikai no Senki"),
+                       main_title(4, "Cowboy Bebop"),
+                       main_title(6, "Macross Plus")];
+
+        let diffs = diff(old, new).unwrap();
+        let summary = diffs.iter().map(|d| (d.id, d.kind)).collect::<Vec<_>>();
+
+        // Anime 4 is unchanged, so it's left out
+        assert_eq!(summary,
+                   vec![(1, DiffKind::Removed),
+                        (2, DiffKind::Changed),
+                        (3, DiffKind::Added),
+                        (5, DiffKind::Removed),
+                        (6, DiffKind::Added)]);
+
+        assert_eq!(describe(&diffs[0].titles),
+                   vec!["removed x-jat main: Seikai no Monshou -> -"]);
+        assert_eq!(describe(&diffs[1].titles),
+                   vec!["changed x-jat main: Shinseiki Evangelion -> Shin Seiki Evangelion"]);
+        assert_eq!(describe(&diffs[2].titles),
+                   vec!["added x-jat main: - -> Seikai no Senki"]);
+    }
+
+    #[test]
+    fn pairs_titles_within_a_changed_anime() {
+        let old = vec![main_title(1, "Seikai no Monshou"),
+                       title(1, TitleType::Synonym, "en", "Crest of the Stars"),
+                       title(1, TitleType::Synonym, "en", "Seikai"),
+                       title(1, TitleType::Official, "ja", "星界の紋章"),
+                       title(1, TitleType::Synonym, "x-jat", "A"),
+                       title(1, TitleType::Synonym, "x-jat", "B")];
+        let new = vec![main_title(1, "Seikai no Monshou"),
+                       title(1, TitleType::Synonym, "en", "Seikai"),
+                       title(1, TitleType::Synonym, "en", "Crest of Stars"),
+                       title(1, TitleType::Short, "en", "SnM"),
+                       title(1, TitleType::Short, "en", "CotS"),
+                       title(1, TitleType::Kana, "ja", "せいかいのもんしょう"),
+                       title(1, TitleType::Synonym, "x-jat", "C")];
+
+        let diffs = diff(old, new).unwrap();
+
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].kind, DiffKind::Changed);
+
+        // Ordered by language and type. Only a single title replaced by a
+        // single title (of the same language and type) counts as changed.
+        assert_eq!(describe(&diffs[0].titles),
+                   vec!["added en short: - -> SnM",
+                        "added en short: - -> CotS",
+                        "changed en syn: Crest of the Stars -> Crest of Stars",
+                        "added ja kana: - -> せいかいのもんしょう",
+                        "removed ja official: 星界の紋章 -> -",
+                        "removed x-jat syn: A -> -",
+                        "removed x-jat syn: B -> -",
+                        "added x-jat syn: - -> C"]);
+    }
+
+    #[test]
+    fn counts_duplicate_titles() {
+        let old = vec![main_title(1, "Seikai no Monshou"), main_title(1, "Seikai no Monshou")];
+        let new = vec![main_title(1, "Seikai no Monshou")];
+
+        let diffs = diff(old, new).unwrap();
+        assert_eq!(describe(&diffs[0].titles),
+                   vec!["removed x-jat main: Seikai no Monshou -> -"]);
+    }
+
+    #[test]
+    fn finds_nothing_in_identical_dumps() {
+        let titles = || vec![main_title(1, "Seikai no Monshou"), main_title(2, "Trigun")];
+
+        assert!(diff(titles(), titles()).unwrap().is_empty());
+        let empty = || Vec::<Result<Title>>::new();
+        assert!(diff(empty(), empty()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn fails_on_bad_input() {
+        let bad = vec![main_title(1, "Seikai no Monshou"),
+                       Err(ErrorKind::InvalidLine(5, "bad".to_string()).into())];
+        assert!(diff(bad, vec![main_title(1, "Seikai no Monshou")]).is_err());
+
+        let unsorted = vec![main_title(2, "Trigun"), main_title(1, "Seikai no Monshou")];
+        match *diff(Vec::<Result<Title>>::new(), unsorted).unwrap_err().kind() {
+            ErrorKind::UnsortedTitles(1, 2) => {}
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+}
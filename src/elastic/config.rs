@@ -0,0 +1,568 @@
+use error::*;
+use serde_json;
+use serde_json::Value as JsValue;
+use serde_yaml;
+use std::fs::File;
+use std::path::Path;
+use super::ServerVersion;
+
+/// Settings and mappings for new indices. Mappings are written without a
+/// mapping type (i.e. `{"properties": {...}}`), and `Client` nests them under
+/// the type on servers that still have them.
+///
+/// A file looks like (in JSON, or the equivalent YAML):
+///
+/// ```text
+/// {
+///   "settings": { "analysis": { "analyzer": { "romaji": { ... } } } },
+///   "mappings": {
+///     "dynamic_templates": [ ... ],
+///     "properties": {
+///       "main_title": { "type": "keyword" },
+///       "normalized_titles": { "type": "keyword" },
+///       "titles": { "properties": { "ja": { "type": "text", "analyzer": "cjk" } } }
+///     }
+///   }
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct IndexConfig {
+    pub settings: JsValue,
+    pub mappings: JsValue,
+}
+
+impl IndexConfig {
+    /// The built-in settings and mappings. Titles in languages without an
+    /// explicit mapping are mapped by dynamic templates: Chinese and Korean
+    /// with the `cjk` analyzer, other romanizations (like `x-zht`) with the
+    /// `romaji` analyzer, and everything else with the `standard` analyzer.
+    pub fn default_for(version: ServerVersion) -> Self {
+        let keyword = if version.has_text_fields() {
+            json!({ "type": "keyword" })
+        } else {
+            json!({ "type": "string", "index": "not_analyzed" })
+        };
+
+        let text_type = if version.has_text_fields() { "text" } else { "string" };
+        let text = |analyzer: &str| json!({ "type": text_type, "analyzer": analyzer });
+
+        let template = |path: &str, analyzer: &str| {
+            json!({
+                "path_match": path,
+                "match_mapping_type": "string",
+                "mapping": text(analyzer)
+            })
+        };
+
+        let mut mappings = json!({
+            // The first matching template is used, so the catch-all is last
+            "dynamic_templates": [
+                { "chinese_titles": template("titles.zh*", "cjk") },
+                { "korean_titles": template("titles.ko", "cjk") },
+                { "romanized_titles": template("titles.x-*", "romaji") },
+                { "other_titles": template("titles.*", "standard") }
+            ],
+            "properties": {
+                "main_title": keyword.clone(),
+                "normalized_titles": keyword,
+                "titles": {
+                    "properties": {
+                        "x-jat": text("romaji"),
+                        "ja": text("cjk"),
+                        "en": text("english"),
+                        "clubdam": text("cjk")
+                    }
+                }
+            }
+        });
+
+        if version.has_all_field() {
+            if let Some(mappings) = mappings.as_object_mut() {
+                mappings.insert("_all".to_string(), json!({ "enabled": false }));
+            }
+        }
+
+        IndexConfig {
+            settings: json!({
+                "analysis": {
+                    "analyzer": {
+                        "romaji": {
+                            "type": "custom",
+                            "tokenizer": "standard",
+                            "char_filter": [],
+                            "filter": ["word_delimiter", "lowercase"]
+                        }
+                    }
+                }
+            }),
+            mappings: mappings,
+        }
+    }
+
+    /// Loads and validates settings and mappings from a JSON file, or a YAML
+    /// file if the extension is `.yml` or `.yaml`. Checks that depend on the
+    /// server's version are left to `validate_for`.
+    pub fn from_file<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let open_err = || format!("failed to read index config {}", path.display());
+        let file = File::open(path).chain_err(&open_err)?;
+
+        let json = match path.extension().and_then(|e| e.to_str()) {
+            Some("yml") | Some("yaml") => {
+                serde_yaml::from_reader::<_, JsValue>(file).chain_err(&open_err)?
+            }
+            _ => serde_json::from_reader::<_, JsValue>(file).chain_err(&open_err)?,
+        };
+
+        IndexConfig::from_json(json)
+    }
+
+    /// Validates settings and mappings given as a JSON object with
+    /// `settings` and `mappings` keys.
+    pub fn from_json(json: JsValue) -> Result<Self> {
+        let mut object = match json {
+            JsValue::Object(object) => object,
+            _ => return Err(invalid("expected an object with \"settings\" and \"mappings\"")),
+        };
+
+        if let Some(key) = object.keys().find(|k| *k != "settings" && *k != "mappings") {
+            return Err(invalid(&format!("unexpected key \"{}\"", key)));
+        }
+
+        let settings = object.remove("settings").unwrap_or_else(|| json!({}));
+        if !settings.is_object() {
+            return Err(invalid("\"settings\" should be an object"));
+        }
+
+        let mappings = match object.remove("mappings") {
+            Some(mappings) => mappings,
+            None => return Err(invalid("missing \"mappings\"")),
+        };
+
+        let config = IndexConfig {
+            settings: settings,
+            mappings: mappings,
+        };
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that the mappings can be used on the given server: field
+    /// types have to be `text`/`keyword` or `string` depending on the
+    /// version, and `_all` can only be set on servers that still have it.
+    pub fn validate_for(&self, version: ServerVersion) -> Result<()> {
+        if self.mappings.get("_all").is_some() && !version.has_all_field() {
+            return Err(invalid(&format!("{} doesn't have an \"_all\" field", version)));
+        }
+
+        check_field_types(&self.mappings, "mappings", version)?;
+
+        for field in &["main_title", "normalized_titles"] {
+            let mapping = self.mappings.get("properties").and_then(|p| p.get(*field));
+            let field_type = mapping.and_then(|f| f.get("type")).and_then(|t| t.as_str());
+            let index = mapping.and_then(|f| f.get("index")).and_then(|i| i.as_str());
+
+            let is_keyword = if version.has_text_fields() {
+                field_type == Some("keyword")
+            } else {
+                field_type == Some("string") && index == Some("not_analyzed")
+            };
+
+            if !is_keyword {
+                let keyword = if version.has_text_fields() {
+                    "{\"type\": \"keyword\"}"
+                } else {
+                    "{\"type\": \"string\", \"index\": \"not_analyzed\"}"
+                };
+
+                return Err(invalid(&format!("\"{}\" should be mapped as {} on {}, for exact \
+                                             matching",
+                                            field,
+                                            keyword,
+                                            version)));
+            }
+        }
+
+        Ok(())
+    }
+
+    // Checks for mistakes that would otherwise only show up as bad matches:
+    // the fields used for exact matching and titles must be mapped, and the
+    // mappings can't have a mapping type
+    fn validate(&self) -> Result<()> {
+        let mappings = match self.mappings.as_object() {
+            Some(mappings) => mappings,
+            None => return Err(invalid("\"mappings\" should be an object")),
+        };
+
+        let properties = match mappings.get("properties").and_then(|p| p.as_object()) {
+            Some(properties) => properties,
+            None => {
+                let mapping_type = mappings.iter()
+                    .find(|&(_, mapping)| mapping.get("properties").is_some())
+                    .map(|(name, _)| name);
+
+                return Err(match mapping_type {
+                    Some(name) => {
+                        invalid(&format!("\"mappings\" should be written without a mapping \
+                                          type (found \"{}\")",
+                                         name))
+                    }
+                    None => invalid("\"mappings\" should have \"properties\""),
+                });
+            }
+        };
+
+        for field in &["main_title", "normalized_titles"] {
+            if properties.get(*field).and_then(|f| f.get("type")).is_none() {
+                return Err(invalid(&format!("\"{}\" should be mapped, for exact matching",
+                                            field)));
+            }
+        }
+
+        if !properties.get("titles").map_or(false, |t| t.is_object()) {
+            return Err(invalid("\"titles\" should be mapped"));
+        }
+
+        if let Some(templates) = mappings.get("dynamic_templates") {
+            if !templates.is_array() {
+                return Err(invalid("\"dynamic_templates\" should be an array"));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    ErrorKind::InvalidIndexConfig(reason.to_string()).into()
+}
+
+// Looks for field types that the server doesn't know, in field mappings and
+// dynamic templates alike. `path` is where `value` is, for error messages.
+fn check_field_types(value: &JsValue, path: &str, version: ServerVersion) -> Result<()> {
+    match *value {
+        JsValue::Object(ref object) => {
+            if let Some(field_type) = object.get("type").and_then(|t| t.as_str()) {
+                let replacement = match field_type {
+                    "string" if version.has_text_fields() => Some("\"text\" or \"keyword\""),
+                    "text" | "keyword" if !version.has_text_fields() => Some("\"string\""),
+                    _ => None,
+                };
+
+                if let Some(replacement) = replacement {
+                    return Err(invalid(&format!("{} doesn't support \"{}\" fields (in {}), use \
+                                                 {} instead",
+                                                version,
+                                                field_type,
+                                                path,
+                                                replacement)));
+                }
+            }
+
+            // `_meta` is free-form
+            for (key, value) in object.iter().filter(|&(key, _)| key != "_meta") {
+                check_field_types(value, &format!("{}.{}", path, key), version)?;
+            }
+        }
+        JsValue::Array(ref array) => {
+            for (i, value) in array.iter().enumerate() {
+                check_field_types(value, &format!("{}[{}]", path, i), version)?;
+            }
+        }
+        _ => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elastic::Distribution;
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use time;
+
+    fn elasticsearch(major: u32) -> ServerVersion {
+        ServerVersion {
+            distribution: Distribution::Elasticsearch,
+            major: major,
+        }
+    }
+
+    fn field_type(config: &IndexConfig, pointer: &str) -> Option<String> {
+        config.mappings.pointer(pointer).and_then(|t| t.as_str()).map(|t| t.to_string())
+    }
+
+    #[test]
+    fn default_uses_keyword_and_text_fields() {
+        let opensearch = ServerVersion {
+            distribution: Distribution::OpenSearch,
+            major: 2,
+        };
+
+        for &version in &[elasticsearch(5), elasticsearch(6), elasticsearch(7), elasticsearch(8),
+                          opensearch] {
+            let config = IndexConfig::default_for(version);
+
+            assert_eq!(field_type(&config, "/properties/main_title/type"),
+                       Some("keyword".to_string()));
+            assert_eq!(field_type(&config, "/properties/titles/properties/ja/type"),
+                       Some("text".to_string()));
+            assert_eq!(field_type(&config, "/properties/titles/properties/ja/analyzer"),
+                       Some("cjk".to_string()));
+        }
+    }
+
+    #[test]
+    fn default_uses_string_fields_before_elasticsearch_5() {
+        let config = IndexConfig::default_for(elasticsearch(2));
+
+        assert_eq!(field_type(&config, "/properties/main_title/type"),
+                   Some("string".to_string()));
+        assert_eq!(field_type(&config, "/properties/main_title/index"),
+                   Some("not_analyzed".to_string()));
+        assert_eq!(field_type(&config, "/properties/titles/properties/en/type"),
+                   Some("string".to_string()));
+    }
+
+    #[test]
+    fn default_disables_all_field_only_where_it_exists() {
+        assert_eq!(IndexConfig::default_for(elasticsearch(5)).mappings.pointer("/_all/enabled"),
+                   Some(&JsValue::Bool(false)));
+
+        for &major in &[6, 7, 8] {
+            assert!(IndexConfig::default_for(elasticsearch(major)).mappings.get("_all").is_none());
+        }
+    }
+
+    #[test]
+    fn default_maps_other_languages_with_templates() {
+        let config = IndexConfig::default_for(elasticsearch(7));
+        let templates = config.mappings
+            .get("dynamic_templates")
+            .and_then(|t| t.as_array())
+            .unwrap();
+
+        let names = templates.iter()
+            .filter_map(|t| t.as_object().and_then(|o| o.keys().next()).cloned())
+            .collect::<Vec<_>>();
+
+        // The catch-all has to come last
+        assert_eq!(names,
+                   vec!["chinese_titles", "korean_titles", "romanized_titles", "other_titles"]);
+    }
+
+    fn reason<T>(result: Result<T>) -> String {
+        match result {
+            Err(e) => {
+                match *e.kind() {
+                    ErrorKind::InvalidIndexConfig(ref reason) => reason.clone(),
+                    ref kind => panic!("unexpected error: {}", kind),
+                }
+            }
+            Ok(_) => panic!("expected an invalid config"),
+        }
+    }
+
+    fn minimal_mappings() -> JsValue {
+        json!({
+            "properties": {
+                "main_title": { "type": "keyword" },
+                "normalized_titles": { "type": "keyword" },
+                "titles": { "properties": { "ja": { "type": "text", "analyzer": "cjk" } } }
+            }
+        })
+    }
+
+    fn from_file(extension: &str, contents: &str) -> Result<IndexConfig> {
+        let path = env::temp_dir()
+            .join(format!("index_config_test_{}.{}", time::precise_time_ns(), extension));
+        fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+
+        let config = IndexConfig::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    #[test]
+    fn default_is_valid_for_its_version() {
+        let opensearch = ServerVersion {
+            distribution: Distribution::OpenSearch,
+            major: 1,
+        };
+
+        for &version in &[elasticsearch(2), elasticsearch(5), elasticsearch(6), elasticsearch(7),
+                          elasticsearch(8), opensearch] {
+            let config = IndexConfig::default_for(version);
+
+            IndexConfig::from_json(json!({
+                    "settings": config.settings,
+                    "mappings": config.mappings
+                }))
+                .unwrap()
+                .validate_for(version)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert_eq!(reason(IndexConfig::from_json(json!([]))),
+                   "expected an object with \"settings\" and \"mappings\"");
+        assert_eq!(reason(IndexConfig::from_json(json!({ "mappings": {}, "aliases": {} }))),
+                   "unexpected key \"aliases\"");
+        assert_eq!(reason(IndexConfig::from_json(json!({ "settings": [] }))),
+                   "\"settings\" should be an object");
+        assert_eq!(reason(IndexConfig::from_json(json!({ "settings": {} }))),
+                   "missing \"mappings\"");
+    }
+
+    #[test]
+    fn rejects_mapping_types() {
+        let config = json!({ "mappings": { "series": minimal_mappings() } });
+
+        assert_eq!(reason(IndexConfig::from_json(config)),
+                   "\"mappings\" should be written without a mapping type (found \"series\")");
+    }
+
+    #[test]
+    fn rejects_missing_fields() {
+        let mut mappings = minimal_mappings();
+        mappings["properties"].as_object_mut().unwrap().remove("normalized_titles");
+
+        assert_eq!(reason(IndexConfig::from_json(json!({ "mappings": mappings }))),
+                   "\"normalized_titles\" should be mapped, for exact matching");
+
+        let mut mappings = minimal_mappings();
+        mappings["properties"].as_object_mut().unwrap().remove("titles");
+
+        assert_eq!(reason(IndexConfig::from_json(json!({ "mappings": mappings }))),
+                   "\"titles\" should be mapped");
+
+        let mut mappings = minimal_mappings();
+        mappings.as_object_mut().unwrap().insert("dynamic_templates".to_string(), json!({}));
+
+        assert_eq!(reason(IndexConfig::from_json(json!({ "mappings": mappings }))),
+                   "\"dynamic_templates\" should be an array");
+    }
+
+    #[test]
+    fn loads_json_and_yaml_files() {
+        let json = r#"{
+            "settings": { "number_of_shards": 1 },
+            "mappings": {
+                "properties": {
+                    "main_title": { "type": "keyword" },
+                    "normalized_titles": { "type": "keyword" },
+                    "titles": { "properties": { "ja": { "type": "text", "analyzer": "cjk" } } }
+                }
+            }
+        }"#;
+        let yaml = "settings:\n  number_of_shards: 1\nmappings:\n  properties:\n    \
+                    main_title: { type: keyword }\n    normalized_titles: { type: keyword }\n    \
+                    titles:\n      properties:\n        ja: { type: text, analyzer: cjk }\n";
+
+        let from_json = from_file("json", json).unwrap();
+        let from_yaml = from_file("yml", yaml).unwrap();
+
+        assert_eq!(from_json.mappings, minimal_mappings());
+        assert_eq!(from_yaml.mappings, minimal_mappings());
+        assert_eq!(from_yaml.settings, json!({ "number_of_shards": 1 }));
+    }
+
+    #[test]
+    fn validates_yaml_files() {
+        let yaml = "mappings:\n  series:\n    properties:\n      main_title: { type: keyword }\n";
+
+        assert_eq!(reason(from_file("yaml", yaml)),
+                   "\"mappings\" should be written without a mapping type (found \"series\")");
+        assert_eq!(reason(from_file("yml", "settings: {}\n")), "missing \"mappings\"");
+    }
+
+    #[test]
+    fn fails_on_unparseable_files() {
+        assert!(from_file("json", "{ \"mappings\": ").is_err());
+        assert!(from_file("yml", "mappings: [").is_err());
+
+        // Anything but `.yml`/`.yaml` is read as JSON
+        assert!(from_file("txt", "mappings: {}").is_err());
+    }
+
+    #[test]
+    fn checks_keyword_fields_by_version() {
+        let config = IndexConfig::from_json(json!({ "mappings": minimal_mappings() })).unwrap();
+
+        assert!(config.validate_for(elasticsearch(7)).is_ok());
+        assert_eq!(reason(config.validate_for(elasticsearch(2))),
+                   "Elasticsearch 2 doesn't support \"keyword\" fields (in \
+                    mappings.properties.main_title), use \"string\" instead");
+
+        let mut mappings = minimal_mappings();
+        mappings["properties"]["normalized_titles"] = json!({ "type": "text" });
+        let config = IndexConfig::from_json(json!({ "mappings": mappings })).unwrap();
+
+        assert_eq!(reason(config.validate_for(elasticsearch(7))),
+                   "\"normalized_titles\" should be mapped as {\"type\": \"keyword\"} on \
+                    Elasticsearch 7, for exact matching");
+    }
+
+    #[test]
+    fn checks_string_fields_by_version() {
+        let mut mappings = minimal_mappings();
+        mappings["properties"]["main_title"] = json!({ "type": "string", "index": "not_analyzed" });
+        mappings["properties"]["normalized_titles"] = json!({ "type": "string" });
+        mappings["properties"]["titles"]["properties"]["ja"] = json!({ "type": "string" });
+        let config = IndexConfig::from_json(json!({ "mappings": mappings })).unwrap();
+
+        assert_eq!(reason(config.validate_for(elasticsearch(2))),
+                   "\"normalized_titles\" should be mapped as {\"type\": \"string\", \
+                    \"index\": \"not_analyzed\"} on Elasticsearch 2, for exact matching");
+        assert_eq!(reason(config.validate_for(elasticsearch(6))),
+                   "Elasticsearch 6 doesn't support \"string\" fields (in \
+                    mappings.properties.main_title), use \"text\" or \"keyword\" instead");
+    }
+
+    #[test]
+    fn checks_dynamic_template_types() {
+        let templates = json!([{
+            "other_titles": {
+                "path_match": "titles.*",
+                "match_mapping_type": "string",
+                "mapping": { "type": "string", "analyzer": "standard" }
+            }
+        }]);
+
+        let mut mappings = minimal_mappings();
+        mappings.as_object_mut().unwrap().insert("dynamic_templates".to_string(), templates);
+        let config = IndexConfig::from_json(json!({ "mappings": mappings })).unwrap();
+
+        assert_eq!(reason(config.validate_for(elasticsearch(7))),
+                   "Elasticsearch 7 doesn't support \"string\" fields (in \
+                    mappings.dynamic_templates[0].other_titles.mapping), use \"text\" or \
+                    \"keyword\" instead");
+    }
+
+    #[test]
+    fn checks_all_field_by_version() {
+        let mut mappings = minimal_mappings();
+        mappings.as_object_mut().unwrap().insert("_all".to_string(), json!({ "enabled": false }));
+        let config = IndexConfig::from_json(json!({ "mappings": mappings })).unwrap();
+
+        assert!(config.validate_for(elasticsearch(5)).is_ok());
+        assert_eq!(reason(config.validate_for(elasticsearch(6))),
+                   "Elasticsearch 6 doesn't have an \"_all\" field");
+
+        let opensearch = ServerVersion {
+            distribution: Distribution::OpenSearch,
+            major: 2,
+        };
+        assert_eq!(reason(config.validate_for(opensearch)),
+                   "OpenSearch 2 doesn't have an \"_all\" field");
+    }
+}
@@ -1,32 +1,75 @@
-use Title;
+use {AnimeEntry, DumpMetadata, Title, TitlePriority};
+use crossbeam;
 use error::*;
 use itertools::Itertools;
+use matcher::{Candidate, Matcher};
+use normalize::normalize;
+use romaji;
 use reqwest;
 use reqwest::Method;
 use serde_json;
 use serde_json::Value as JsValue;
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc;
+use std::thread;
 use time;
 
+mod config;
+mod retry;
+mod version;
 
-#[derive(Debug, Deserialize, Serialize)]
+pub use self::config::IndexConfig;
+pub use self::retry::RetryPolicy;
+pub use self::version::{Distribution, ServerVersion};
+
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Series {
     pub id: String,
     pub main_title: Option<String>,
     pub titles: TitlesByLanguage,
+    /// Every title, passed through `normalize::normalize`, for exact matching
+    #[serde(default)]
+    pub normalized_titles: Vec<String>,
+}
+
+impl Series {
+    /// Builds a series document from an anime's titles, using its
+    /// highest-priority Japanese title as the main title.
+    pub fn from_anime(entry: AnimeEntry, priority: &TitlePriority) -> Self {
+        let titles_by_language = TitlesByLanguage::with_priority(entry.titles, priority);
+        let main_title = titles_by_language.main_title("ja");
+        let normalized_titles = titles_by_language.normalized();
+
+        Series {
+            id: entry.id.to_string(),
+            main_title: main_title,
+            titles: titles_by_language,
+            normalized_titles: normalized_titles,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct TitlesByLanguage(pub HashMap<String, Vec<String>>);
 
 impl TitlesByLanguage {
-    pub fn new(mut titles: Vec<Title>) -> Self {
+    pub fn new(titles: Vec<Title>) -> Self {
+        TitlesByLanguage::with_priority(titles, &TitlePriority::default())
+    }
+
+    /// Groups titles by language, with each language's titles ordered by
+    /// the given priority of their types.
+    pub fn with_priority(mut titles: Vec<Title>, priority: &TitlePriority) -> Self {
         let mut by_language: HashMap<String, Vec<String>> = HashMap::new();
 
-        titles.sort_by_key(|t| t.title_type as i8);
+        titles.sort_by_key(|t| priority.rank(&t.title_type));
 
-        while let Some(title) = titles.pop() {
+        for title in titles {
             match by_language.entry(title.language) {
                 Entry::Occupied(mut o) => {
                     o.get_mut().push(title.title);
@@ -40,6 +83,19 @@ impl TitlesByLanguage {
         TitlesByLanguage(by_language)
     }
 
+    /// All titles (in any language) normalized for matching, without
+    /// duplicates.
+    pub fn normalized(&self) -> Vec<String> {
+        let mut normalized = self.0
+            .values()
+            .flat_map(|titles| titles.iter().map(|t| normalize(t)))
+            .collect::<Vec<_>>();
+
+        normalized.sort();
+        normalized.dedup();
+        normalized
+    }
+
     pub fn main_title<S>(&self, language: S) -> Option<String>
         where S: Into<String>
     {
@@ -52,51 +108,197 @@ pub struct Client<'a> {
     base_url: &'a str,
     alias: &'a str,
     type_name: &'a str,
+    retry: RetryPolicy,
+    version: ServerVersion,
+    index_config: Option<IndexConfig>,
 }
 
 impl<'a> Client<'a> {
-    pub fn new(base_url: &'a str, alias: &'a str, type_name: &'a str) -> Result<Self> {
-        Ok(Client {
+    /// Connects to the server to detect its version. `type_name` is the
+    /// mapping type, which is only used by servers that still have them. The
+    /// retry policy applies to every request, including the first one.
+    pub fn new(base_url: &'a str,
+               alias: &'a str,
+               type_name: &'a str,
+               retry: RetryPolicy)
+               -> Result<Self> {
+        let mut client = Client {
             http: reqwest::Client::new()?,
             base_url: base_url,
             alias: alias,
             type_name: type_name,
-        })
+            retry: retry,
+            // Replaced below, once the server has told us its version
+            version: ServerVersion {
+                distribution: Distribution::Elasticsearch,
+                major: 5,
+            },
+            index_config: None,
+        };
+
+        let info = client.do_request(Method::Get, "", None)?.json::<JsValue>()?;
+        client.version = ServerVersion::from_info(&info)?;
+
+        Ok(client)
     }
 
-    pub fn reindex<I>(&self, series: I, chunk_size: usize, should_wait: bool) -> Result<Vec<String>>
-        where I: IntoIterator<Item = Series>
+    pub fn version(&self) -> ServerVersion {
+        self.version
+    }
+
+    /// Uses the given settings and mappings for new indices, instead of
+    /// `IndexConfig::default_for` the server's version. Fails if they can't
+    /// be used with the server's version.
+    pub fn with_index_config(mut self, index_config: IndexConfig) -> Result<Self> {
+        index_config.validate_for(self.version)?;
+        self.index_config = Some(index_config);
+        Ok(self)
+    }
+
+    /// Indexes `series` into a new index, with up to `concurrency` bulk
+    /// requests of `chunk_size` documents in flight at a time, and returns
+    /// its name. The index is refreshed once at the end, but isn't added to
+    /// the alias until `swap_alias` is called, so it can still be checked (and
+    /// deleted if there's something wrong) without affecting searches.
+    pub fn build_index<I>(&self,
+                          series: I,
+                          metadata: &DumpMetadata,
+                          chunk_size: usize,
+                          concurrency: usize)
+                          -> Result<String>
+        where I: IntoIterator<Item = Result<Series>>
     {
         let now = time::now_utc();
         let now_str = now.strftime("%Y%m%d_%H%M%S").unwrap();
         let index_name = format!("{}_{}", self.alias, now_str);
 
-        println!("Getting indices for alias \"{}\"", self.alias);
-        let existing_indexes = self.get_indexes_for_alias()?;
-
         println!("Creating new index \"{}\"", index_name);
-        self.new_index(&index_name)?;
+        self.new_index(&index_name, metadata)?;
 
         println!("Bulk inserting documents");
-        for chunk in &series.into_iter().chunks(chunk_size) {
-            self.bulk_insert(&index_name, chunk, should_wait)?;
+        // Series are read lazily, so a parse error can happen partway through.
+        // Don't leave a half-populated index lying around if that happens.
+        let result = self.bulk_insert_all(&index_name, series, chunk_size, concurrency)
+            .and_then(|_| self.refresh(&index_name));
+
+        if let Err(e) = result {
+            self.discard_index(&index_name);
+            return Err(e);
         }
 
+        Ok(index_name)
+    }
+
+    /// Points the alias at `new_index` (built by `build_index`), removing it
+    /// from the indices it pointed at before. Returns those old indices.
+    pub fn swap_alias(&self, new_index: &str) -> Result<Vec<String>> {
+        println!("Getting indices for alias \"{}\"", self.alias);
+        let existing_indexes = self.get_indexes_for_alias()?;
+
         println!("Updating alias \"{}\" to point to \"{}\", and removing old aliases {:?}",
                  self.alias,
-                 index_name,
+                 new_index,
                  existing_indexes);
-        self.update_alias(index_name, &existing_indexes)?;
+        self.update_alias(new_index, &existing_indexes)?;
 
         Ok(existing_indexes)
     }
 
-    fn update_alias<T>(&self, new_index: T, old_indexes: &[T]) -> Result<()>
+    /// Deletes an index from `build_index` that won't be used after all. This
+    /// is only cleanup, so failures are ignored.
+    pub fn discard_index(&self, index_name: &str) {
+        println!("Deleting unused index \"{}\"", index_name);
+        let _ = self.delete_indices(&[index_name]);
+    }
+
+    // Chunks are read on the calling thread and handed to `concurrency`
+    // worker threads, each sending one bulk request at a time. Handing over a
+    // chunk blocks until a worker is free, so reading can't get ahead of
+    // indexing. Reading stops at the first failure, and if several chunks
+    // fail, the error for the earliest one (in input order) is returned.
+    fn bulk_insert_all<I>(&self,
+                          index_name: &str,
+                          series: I,
+                          chunk_size: usize,
+                          concurrency: usize)
+                          -> Result<()>
+        where I: IntoIterator<Item = Result<Series>>
+    {
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel::<(usize, Vec<Series>)>(0);
+        let chunk_rx = Arc::new(Mutex::new(chunk_rx));
+        let (result_tx, result_rx) = mpsc::channel::<(usize, Result<()>)>();
+
+        let mut errors = crossbeam::scope(|scope| {
+            for _ in 0..cmp::max(concurrency, 1) {
+                let chunk_rx = chunk_rx.clone();
+                let result_tx = result_tx.clone();
+
+                scope.spawn(move || {
+                    loop {
+                        let (i, chunk) = match chunk_rx.lock().unwrap().recv() {
+                            Ok(next) => next,
+                            Err(_) => break, // No more chunks
+                        };
+
+                        let result = self.bulk_insert(index_name, chunk, false);
+                        if result_tx.send((i, result)).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+
+            // Only the workers should keep the channels open, so that sending a
+            // chunk fails if they've all exited, and results end when they do
+            drop(chunk_rx);
+            drop(result_tx);
+
+            let failures = |(i, result): (usize, Result<()>)| result.err().map(|e| (i, e));
+            let mut errors = Vec::new();
+
+            let chunks = series.into_iter().chunks(chunk_size);
+            for (i, chunk) in chunks.into_iter().enumerate() {
+                errors.extend(result_rx.try_iter().filter_map(&failures));
+                if !errors.is_empty() {
+                    break;
+                }
+
+                let sent = chunk.collect::<Result<Vec<Series>>>()
+                    .and_then(|chunk| {
+                        chunk_tx.send((i, chunk))
+                            .map_err(|_| "bulk insert workers exited unexpectedly".into())
+                    });
+
+                if let Err(e) = sent {
+                    errors.push((i, e));
+                    break;
+                }
+            }
+
+            // Let the workers finish their in-flight requests and exit
+            drop(chunk_tx);
+            errors.extend(result_rx.iter().filter_map(&failures));
+            errors
+        });
+
+        errors.sort_by_key(|&(i, _)| i);
+
+        match errors.into_iter().next() {
+            Some((_, e)) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn refresh(&self, index_name: &str) -> Result<()> {
+        self.do_request(Method::Post, &format!("{}/_refresh", index_name), None).map(|_| ())
+    }
+
+    fn update_alias<T>(&self, new_index: &str, old_indexes: &[T]) -> Result<()>
         where T: AsRef<str>
     {
         let actions = old_indexes.iter()
             .map(|index| ("remove", index.as_ref()))
-            .chain(Some(("add", new_index.as_ref())))
+            .chain(Some(("add", new_index)))
             .map(|(op, index)| json!({ op: { "index": index, "alias": self.alias } }));
 
         let body = json!({
@@ -104,7 +306,11 @@ impl<'a> Client<'a> {
         });
 
         let json = serde_json::to_string(&body)?;
-        self.do_request(Method::Post, "_aliases", Some(&json)).map(|_| ())
+
+        // Not retried: if the server applied the actions but the response was
+        // lost, a retry would apply them again
+        self.request_with_retry(Method::Post, "_aliases", Some(&json), &RetryPolicy::no_retries())
+            .map(|_| ())
     }
 
     pub fn delete_indices<T>(&self, indices: &[T]) -> Result<()>
@@ -118,26 +324,23 @@ impl<'a> Client<'a> {
     pub fn bulk_insert<I>(&self, index_name: &str, items: I, should_wait: bool) -> Result<()>
         where I: IntoIterator<Item = Series>
     {
-        let mut body = items.into_iter()
+        let actions = items.into_iter()
             .map(|series| {
                 let action = json!({ "index": { "_id": series.id } });
 
-                format!(
+                let lines = format!(
                     "{}\n{}",
                     serde_json::to_string(&action).unwrap(),
                     serde_json::to_string(&series).unwrap()
-                )
-            })
-            .join("\n");
+                );
 
-        body.push('\n');
+                (series.id, lines)
+            })
+            .collect();
 
         let wait_for = if should_wait { "?refresh=wait_for" } else { "" };
 
-        self.do_request(Method::Put,
-                        &format!("{}/{}/_bulk{}", index_name, self.type_name, wait_for),
-                        Some(&body))
-            .map(|_| ())
+        self.send_bulk(&self.bulk_path(index_name, wait_for), actions)
     }
 
     pub fn delete_non_clubdam(&self, batch_size: usize) -> Result<()> {
@@ -152,7 +355,7 @@ impl<'a> Client<'a> {
                 }
             },
             "sort": ["_doc"],
-            "fields": [],
+            "_source": false,
             "size": batch_size
         });
 
@@ -163,19 +366,16 @@ impl<'a> Client<'a> {
         };
 
         ids_iter.map(|ids| {
-                let mut body = ids?
-                        .into_iter()
-                        .map(|id| {
-                            let delete = json!({"delete": { "_id": id }});
-                            serde_json::to_string(&delete).unwrap()
-                        })
-                        .join("\n");
-
-                body.push('\n');
+                let actions = ids?
+                    .into_iter()
+                    .map(|id| {
+                        let delete = json!({"delete": { "_id": id }});
+                        let line = serde_json::to_string(&delete).unwrap();
+                        (id, line)
+                    })
+                    .collect();
 
-                self.do_request(Method::Put,
-                                &format!("{}/{}/_bulk", self.alias, self.type_name),
-                                Some(&body))
+                self.send_bulk(&self.bulk_path(self.alias, ""), actions)
             })
             .fold_results((), |_, _| ())
     }
@@ -183,7 +383,7 @@ impl<'a> Client<'a> {
     pub fn bulk_update<I>(&self, items: I, should_wait: bool) -> Result<()>
         where I: IntoIterator<Item = (String, Vec<String>)>
     {
-        let mut body = items.into_iter()
+        let actions = items.into_iter()
             .map(|(id, titles)| {
                 let action = json!({ "update": { "_id": id } });
                 let doc = json!({
@@ -194,22 +394,72 @@ impl<'a> Client<'a> {
                     }
                 });
 
-                format!(
+                let lines = format!(
                     "{}\n{}",
                     serde_json::to_string(&action).unwrap(),
                     serde_json::to_string(&doc).unwrap()
-                )
-            })
-            .join("\n");
+                );
 
-        body.push('\n');
+                (id, lines)
+            })
+            .collect();
 
         let wait_for = if should_wait { "?refresh=wait_for" } else { "" };
 
-        self.do_request(Method::Put,
-                        &format!("{}/{}/_bulk{}", self.alias, self.type_name, wait_for),
-                        Some(&body))
-            .map(|_| ())
+        self.send_bulk(&self.bulk_path(self.alias, wait_for), actions)
+    }
+
+    // `{index}/{type}/_bulk` on servers with mapping types, `{index}/_bulk`
+    // otherwise
+    fn bulk_path(&self, index: &str, query: &str) -> String {
+        if self.version.has_mapping_types() {
+            format!("{}/{}/_bulk{}", index, self.type_name, query)
+        } else {
+            format!("{}/_bulk{}", index, query)
+        }
+    }
+
+    // Sends bulk actions, given as document IDs with their action (and
+    // source) lines. Elasticsearch responds with 200 even if some of the
+    // documents failed, so the response is checked for failures. Documents
+    // that failed with a retryable status (e.g. 429 when the bulk queue is
+    // full) are resent on their own, following the retry policy. Any other
+    // failures, or retryable ones that run out of attempts, are returned as
+    // `BulkItemsFailed`.
+    fn send_bulk(&self, path: &str, mut actions: Vec<(String, String)>) -> Result<()> {
+        let mut attempt = 1;
+
+        loop {
+            let mut body = actions.iter().map(|&(_, ref lines)| lines.as_str()).join("\n");
+            body.push('\n');
+
+            let response = self.do_request(Method::Put, path, Some(&body))?.json::<JsValue>()?;
+            let failures = bulk_failures(&response);
+
+            if failures.is_empty() {
+                return Ok(());
+            }
+
+            let all_retryable = failures.iter().all(|f| self.retry.is_retryable(f.status));
+
+            match self.retry.backoff(attempt) {
+                Some(delay) if all_retryable => {
+                    println!("{} documents in bulk request to {} failed (attempt {} of {}), \
+                              retrying them",
+                             failures.len(),
+                             path,
+                             attempt,
+                             self.retry.max_attempts);
+
+                    let failed_ids = failures.into_iter().map(|f| f.id).collect::<HashSet<_>>();
+                    actions.retain(|&(ref id, _)| failed_ids.contains(id));
+
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                _ => return Err(ErrorKind::BulkItemsFailed(failures).into()),
+            }
+        }
     }
 
     fn get_indexes_for_alias(&self) -> Result<Vec<String>> {
@@ -217,39 +467,124 @@ impl<'a> Client<'a> {
 
         let json = result.json::<JsValue>()?;
 
+        // Only indices with this alias, since other aliases (e.g. the one used
+        // by `evaluate`) can live in the same cluster
+        let alias_pointer = format!("/aliases/{}", self.alias);
+
         if let JsValue::Object(obj) = json {
-            Ok(obj.keys().cloned().collect())
+            Ok(obj.into_iter()
+                .filter(|&(_, ref index)| index.pointer(&alias_pointer).is_some())
+                .map(|(name, _)| name)
+                .collect())
         } else {
             Err(format!("expected JSON object, got {}", json))?
         }
     }
 
-    fn new_index(&self, index_name: &str) -> Result<()> {
-        let json = serde_json::to_string(&mappings())?;
-        self.do_request(Method::Put, index_name, Some(&json)).map(|_| ())
+    fn new_index(&self, index_name: &str, metadata: &DumpMetadata) -> Result<()> {
+        let config = match self.index_config {
+            Some(ref config) => config.clone(),
+            None => IndexConfig::default_for(self.version),
+        };
+
+        let mut mapping = config.mappings;
+
+        // Record which AniDB dump the index was built from in the mapping's
+        // `_meta`, retrievable later via `GET {alias}/_mapping`
+        if let Some(mapping) = mapping.as_object_mut() {
+            mapping.insert("_meta".to_string(), json!({ "anidb_dump": dump_meta(metadata) }));
+        }
+
+        let type_name = self.type_name;
+        let mappings = if self.version.has_mapping_types() {
+            json!({ type_name: mapping })
+        } else {
+            mapping
+        };
+
+        let body = json!({
+            "settings": config.settings,
+            "mappings": mappings
+        });
+
+        let json = serde_json::to_string(&body)?;
+
+        // Not retried: if the server created the index but the response was
+        // lost, a retry would fail since the index already exists
+        self.request_with_retry(Method::Put, index_name, Some(&json), &RetryPolicy::no_retries())
+            .map(|_| ())
     }
 
     // TODO: Make this type signature not terrible
-    pub fn multi_search<T, L, S1, S2>(&self, titles: T, languages: L) -> Result<Vec<Option<Series>>>
+    /// Searches for each title, returning up to `size` candidates per title
+    /// (best first) along with their scores.
+    pub fn multi_search<T, L, S1, S2>(&self,
+                                      titles: T,
+                                      languages: L,
+                                      size: usize)
+                                      -> Result<Vec<Vec<Candidate>>>
         where T: IntoIterator<Item = S1>,
               S1: AsRef<str>,
               L: IntoIterator<Item = S2>,
               S2: AsRef<str>
     {
-        let mut fields =
+        let fields =
             languages.into_iter().map(|l| format!("titles.{}", l.as_ref())).collect::<Vec<_>>();
 
-        // Prioritize exact matches
-        fields.push("main_title^10".to_string());
-
         let mut requests = titles.into_iter()
             .map(|title| {
+                let title = title.as_ref();
+
+                // Each clause is named so that hits say which one matched.
+                // Exact matches are prioritized, and exact matches after
+                // normalization (ignoring width, spacing, etc) rank highest
+                // of all.
+                let mut should = vec![
+                    json!({
+                        "multi_match": {
+                            "query": title,
+                            "fields": fields,
+                            "_name": "titles"
+                        }
+                    }),
+                    json!({
+                        "term": {
+                            "main_title": {
+                                "value": title,
+                                "boost": 10,
+                                "_name": "main_title"
+                            }
+                        }
+                    }),
+                    json!({
+                        "term": {
+                            "normalized_titles": {
+                                "value": normalize(title),
+                                "boost": 20,
+                                "_name": "normalized_titles"
+                            }
+                        }
+                    }),
+                ];
+
+                // Many AniDB entries have no Japanese title but do have a
+                // romanized one, so also try matching kana against that
+                if romaji::has_kana(title) {
+                    should.push(json!({
+                        "match": {
+                            "titles.x-jat": {
+                                "query": romaji::normalized_romaji(title),
+                                "_name": "titles.x-jat"
+                            }
+                        }
+                    }));
+                }
+
                 let query = json!({
-                    "size": 1,
+                    "size": size,
                     "query": {
-                        "multi_match": {
-                            "query": title.as_ref(),
-                            "fields": fields
+                        "bool": {
+                            "should": should
                         }
                     }
                 });
@@ -266,18 +601,42 @@ impl<'a> Client<'a> {
             .json::<JsValue>()?;
 
         let mut empty_vec = Vec::new();
-        let series = result.get_mut("responses")
+        let candidates = result.get_mut("responses")
             .and_then(|r| r.as_array_mut())
             .unwrap_or(&mut empty_vec)
             .iter_mut()
             .map(|json| {
-                json.pointer_mut("/hits/hits/0/_source").and_then(|s| {
-                    let source = ::std::mem::replace(s, JsValue::Null);
-                    serde_json::from_value::<Series>(source).ok() // TODO: Use Result
-                })
+                let mut empty_hits = Vec::new();
+
+                json.pointer_mut("/hits/hits")
+                    .and_then(|h| h.as_array_mut())
+                    .unwrap_or(&mut empty_hits)
+                    .iter_mut()
+                    .flat_map(|hit| {
+                        let score = hit.get("_score").and_then(|s| s.as_f64()).unwrap_or(0.0);
+
+                        let matched_fields = hit.get("matched_queries")
+                            .and_then(|q| q.as_array())
+                            .map(|q| {
+                                q.iter().filter_map(|f| f.as_str()).map(|f| f.to_string()).collect()
+                            })
+                            .unwrap_or_else(Vec::new);
+
+                        hit.get_mut("_source").and_then(|s| {
+                            let source = ::std::mem::replace(s, JsValue::Null);
+                            serde_json::from_value::<Series>(source).ok() // TODO: Use Result
+                        }).map(|series| {
+                            Candidate {
+                                series: series,
+                                score: score,
+                                matched_fields: matched_fields,
+                            }
+                        })
+                    })
+                    .collect::<Vec<_>>()
             });
 
-        Ok(series.collect::<Vec<_>>())
+        Ok(candidates.collect::<Vec<_>>())
     }
 
     fn do_request(&self,
@@ -285,11 +644,28 @@ impl<'a> Client<'a> {
                   path: &'a str,
                   body: Option<&'a str>)
                   -> Result<reqwest::Response> {
+        self.request_with_retry(method, path, body, &self.retry)
+    }
+
+    fn request_with_retry(&self,
+                          method: Method,
+                          path: &'a str,
+                          body: Option<&'a str>,
+                          policy: &RetryPolicy)
+                          -> Result<reqwest::Response> {
         let url_str = format!("{}/{}", self.base_url, path);
 
         let url =
             reqwest::Url::parse(&url_str).chain_err(|| ErrorKind::InvalidUrl(url_str.clone()))?;
 
+        // Newer servers reject bodies without a content type, and the bulk and
+        // multi-search APIs take newline-delimited JSON
+        let content_type = if path.contains("_bulk") || path.contains("_msearch") {
+            "application/x-ndjson"
+        } else {
+            "application/json"
+        };
+
         let auth = if !url.username().is_empty() || !url.password().is_none() {
             use reqwest::header::{Authorization, Basic};
 
@@ -301,30 +677,142 @@ impl<'a> Client<'a> {
             None
         };
 
-        let mut req = self.http.request(method, url);
+        let mut attempt = 1;
 
-        if let Some(b) = body {
-            req = req.body(b);
-        }
+        loop {
+            let mut req = self.http.request(method.clone(), url.clone());
+
+            if let Some(b) = body {
+                let mut headers = reqwest::header::Headers::new();
+                headers.set_raw("Content-Type", vec![content_type.as_bytes().to_vec()]);
+
+                req = req.headers(headers).body(b);
+            }
 
-        if let Some(a) = auth {
-            req = req.header(a);
+            if let Some(ref a) = auth {
+                req = req.header(a.clone());
+            }
+
+            let error: Error = match req.send() {
+                Ok(mut response) => {
+                    use std::io::Read;
+
+                    if response.status().is_success() {
+                        return Ok(response);
+                    }
+
+                    let status = response.status().to_u16();
+                    let mut response_str = String::new();
+                    response.read_to_string(&mut response_str)?;
+
+                    let error = ErrorKind::UnexpectedResponse(url_str.clone(), response_str);
+                    if !policy.is_retryable(status) {
+                        return Err(error.into());
+                    }
+
+                    error.into()
+                }
+                Err(e) => {
+                    if !retry::is_retryable_error(&e) {
+                        return Err(e.into());
+                    }
+
+                    e.into()
+                }
+            };
+
+            match policy.backoff(attempt) {
+                Some(delay) => {
+                    println!("Request to {} failed (attempt {} of {}), retrying: {}",
+                             url_str,
+                             attempt,
+                             policy.max_attempts,
+                             error);
+                    thread::sleep(delay);
+                    attempt += 1;
+                }
+                None => return Err(error),
+            }
         }
+    }
+}
 
-        let mut response = req.send()?;
+/// A document that failed within a bulk request.
+#[derive(Debug, Clone)]
+pub struct BulkItemFailure {
+    pub id: String,
+    pub status: u16,
+    pub reason: String,
+}
 
-        if response.status().is_success() {
-            Ok(response)
-        } else {
-            use std::io::Read;
+impl fmt::Display for BulkItemFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "document {} (status {}): {}", self.id, self.status, self.reason)
+    }
+}
+
+// Finds the failed items in a bulk response, which looks like:
+// `{"errors": true, "items": [{"index": {"_id": "1", "status": 429, "error": {...}}}]}`
+fn bulk_failures(response: &JsValue) -> Vec<BulkItemFailure> {
+    if response.get("errors").and_then(|e| e.as_bool()) != Some(true) {
+        return Vec::new();
+    }
 
-            let mut response_str = String::new();
-            response.read_to_string(&mut response_str)?;
-            Err(ErrorKind::UnexpectedResponse(url_str, response_str).into())
+    let items = response.get("items").and_then(|i| i.as_array()).map_or(&[][..], |i| &i[..]);
+
+    items.iter()
+        .filter_map(|item| {
+            // Each item is keyed by its action ("index", "update", "delete")
+            let result = match item.as_object().and_then(|o| o.values().next()) {
+                Some(result) => result,
+                None => return None,
+            };
+
+            // Errors are objects in newer versions, and strings in older ones
+            let reason = match result.get("error") {
+                Some(&JsValue::String(ref reason)) => reason.clone(),
+                Some(error) => {
+                    error.get("reason")
+                        .and_then(|r| r.as_str())
+                        .map_or_else(|| error.to_string(), |r| r.to_string())
+                }
+                None => return None,
+            };
+
+            Some(BulkItemFailure {
+                id: result.get("_id").and_then(|id| id.as_str()).unwrap_or("").to_string(),
+                status: result.get("status").and_then(|s| s.as_u64()).unwrap_or(0) as u16,
+                reason: reason,
+            })
+        })
+        .collect()
+}
+
+/// Matches titles by searching the indexed series in Elasticsearch.
+pub struct ElasticMatcher<'a> {
+    client: &'a Client<'a>,
+    languages: Vec<String>,
+}
+
+impl<'a> ElasticMatcher<'a> {
+    /// Searches titles in the given languages (e.g. `titles.ja`), in addition
+    /// to the main title and normalized titles.
+    pub fn new<S>(client: &'a Client<'a>, languages: &[S]) -> Self
+        where S: AsRef<str>
+    {
+        ElasticMatcher {
+            client: client,
+            languages: languages.iter().map(|l| l.as_ref().to_string()).collect(),
         }
     }
 }
 
+impl<'a> Matcher for ElasticMatcher<'a> {
+    fn find_candidates(&self, titles: &[String], limit: usize) -> Result<Vec<Vec<Candidate>>> {
+        self.client.multi_search(titles, &self.languages, limit)
+    }
+}
+
 pub struct ScrollSearch<'a> {
     client: &'a Client<'a>,
     query: JsValue,
@@ -340,7 +828,13 @@ impl<'a> Iterator for ScrollSearch<'a> {
                     let q = json!({ "scroll": "1m", "scroll_id": scroll });
                     let body = serde_json::to_string(&q)?;
 
-                    self.client.do_request(Method::Post, "_search/scroll", Some(&body))
+                    // Not retried: if the server handled the request but the
+                    // response was lost, a retry would return the next page,
+                    // silently skipping this one
+                    self.client.request_with_retry(Method::Post,
+                                                   "_search/scroll",
+                                                   Some(&body),
+                                                   &RetryPolicy::no_retries())
                 } else {
                     let body = serde_json::to_string(&self.query)?;
 
@@ -379,50 +873,305 @@ impl<'a> Iterator for ScrollSearch<'a> {
     }
 }
 
-fn mappings() -> serde_json::Value {
+fn dump_meta(metadata: &DumpMetadata) -> serde_json::Value {
+    let created = metadata.created.map(|tm| tm.rfc3339().to_string());
+
     json!({
-        "settings": {
-            "analysis": {
-                "analyzer": {
-                    "romaji": {
-                        "type": "custom",
-                        "tokenizer": "standard",
-                        "char_filter": [],
-                        "filter": ["word_delimiter", "lowercase"]
-                    }
-                }
+        "created": created,
+        "comments": metadata.comments
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    const INFO: &'static str = r#"{"version": {"number": "6.8.0"}}"#;
+
+    fn response(status: u16, body: &str) -> String {
+        let reason = match status {
+            200 => "OK",
+            400 => "Bad Request",
+            503 => "Service Unavailable",
+            _ => "Unknown",
+        };
+
+        format!("HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                 Connection: close\r\n\r\n{}",
+                status,
+                reason,
+                body.len(),
+                body)
+    }
+
+    // Reads a request's head and body, returning them as a string
+    fn read_request(stream: &mut TcpStream) -> String {
+        let mut reader = BufReader::new(stream);
+        let mut request = String::new();
+        let mut content_length = 0;
+
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+
+            if line.to_lowercase().starts_with("content-length:") {
+                content_length = line["content-length:".len()..].trim().parse().unwrap();
             }
-        },
-        "mappings": {
-            "series": {
-                "_all": { "enabled": false },
-                "properties": {
-                    "main_title": {
-                        "type": "string",
-                        "index": "not_analyzed"
-                    },
-                    "titles": {
-                        "properties": {
-                            "x-jat": {
-                                "type": "string",
-                                "analyzer": "romaji"
-                            },
-                            "ja": {
-                                "type": "string",
-                                "analyzer": "cjk"
-                            },
-                            "en": {
-                                "type": "string",
-                                "analyzer": "english"
-                            },
-                            "clubdam": {
-                                "type": "string",
-                                "analyzer": "cjk"
-                            }
-                        }
-                    }
-                }
+
+            request.push_str(&line);
+            if line == "\r\n" || line.is_empty() {
+                break;
             }
         }
-    })
+
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).unwrap();
+        request.push_str(&String::from_utf8(body).unwrap());
+        request
+    }
+
+    // A fake server answering one request per connection with each of
+    // `responses` in turn. Joining the handle gives the requests it received.
+    fn serve(responses: Vec<String>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        let handle = thread::spawn(move || {
+            responses.into_iter()
+                .map(|response| {
+                    let (mut stream, _) = listener.accept().unwrap();
+                    let request = read_request(&mut stream);
+                    stream.write_all(response.as_bytes()).unwrap();
+                    request
+                })
+                .collect()
+        });
+
+        (url, handle)
+    }
+
+    fn fast_retries(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: max_attempts,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(40),
+            retryable_statuses: vec![503],
+        }
+    }
+
+    #[test]
+    fn retries_transient_statuses_with_backoff() {
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(503, "{}"),
+                                       response(503, "{}"),
+                                       response(200, "{}")]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        let start = Instant::now();
+        client.refresh("series_1").unwrap();
+        let elapsed = start.elapsed();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 4);
+        assert!(requests[1..].iter().all(|r| r.starts_with("POST /series_1/_refresh ")));
+
+        // Backoffs of 10-20ms and then 20-40ms
+        assert!(elapsed >= Duration::from_millis(30), "retried after {:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(1000), "retried after {:?}", elapsed);
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(503, "{}"),
+                                       response(503, "{}"),
+                                       response(503, "{}")]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        match *client.refresh("series_1").unwrap_err().kind() {
+            ErrorKind::UnexpectedResponse(..) => {}
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        assert_eq!(server.join().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn doesnt_retry_other_statuses() {
+        let (url, server) = serve(vec![response(200, INFO), response(400, "{}")]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        assert!(client.refresh("series_1").is_err());
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn detects_version_with_given_retry_policy() {
+        let (url, server) = serve(vec![response(503, "{}"), response(200, INFO)]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        assert!(client.version().has_mapping_types());
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn sends_content_types() {
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(200, "{}"),
+                                       response(200, r#"{"errors": false, "items": []}"#)]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        client.update_alias("series_1", &[] as &[&str]).unwrap();
+        let actions = vec![("1".to_string(), "{\"index\":{\"_id\":\"1\"}}\n{}".to_string())];
+        client.send_bulk("series_1/_bulk", actions).unwrap();
+
+        let requests = server.join().unwrap()
+            .into_iter()
+            .map(|r| r.to_lowercase())
+            .collect::<Vec<_>>();
+
+        assert!(!requests[0].contains("content-type:"));
+        assert!(requests[1].contains("content-type: application/json\r\n"));
+        assert!(requests[2].contains("content-type: application/x-ndjson\r\n"));
+    }
+
+    #[test]
+    fn doesnt_retry_scroll_continuation() {
+        let first_page = r#"{"_scroll_id": "abc", "hits": {"hits": [{"_id": "1"}]}}"#;
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(200, first_page),
+                                       response(503, "{}")]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        let mut scroll = ScrollSearch {
+            client: &client,
+            query: json!({}),
+            scroll_id: None,
+        };
+
+        assert_eq!(scroll.next().unwrap().unwrap(), vec!["1".to_string()]);
+        assert!(scroll.next().unwrap().is_err());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[2].starts_with("POST /_search/scroll "));
+    }
+
+    #[test]
+    fn doesnt_retry_index_creation_or_alias_changes() {
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(503, "{}"),
+                                       response(503, "{}")]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        assert!(client.new_index("series_1", &DumpMetadata::default()).is_err());
+        assert!(client.update_alias("series_1", &["series_0"]).is_err());
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].starts_with("PUT /series_1 "));
+        assert!(requests[2].starts_with("POST /_aliases "));
+    }
+
+    const MIXED_BULK_RESPONSE: &'static str = r#"{
+        "took": 30,
+        "errors": true,
+        "items": [
+            {"index": {"_id": "1", "status": 201, "result": "created"}},
+            {"index": {"_id": "2", "status": 429, "error": {
+                "type": "es_rejected_execution_exception",
+                "reason": "rejected execution of bulk"
+            }}},
+            {"index": {"_id": "3", "status": 400, "error": {
+                "type": "mapper_parsing_exception",
+                "reason": "failed to parse [titles.ja]"
+            }}},
+            {"update": {"_id": "4", "status": 400, "error": "MapperParsingException[bad]"}},
+            {"delete": {"_id": "5", "status": 404, "result": "not_found"}}
+        ]
+    }"#;
+
+    #[test]
+    fn finds_failed_items_in_mixed_bulk_response() {
+        let response = serde_json::from_str::<JsValue>(MIXED_BULK_RESPONSE).unwrap();
+        let failures = bulk_failures(&response)
+            .into_iter()
+            .map(|f| (f.id, f.status, f.reason))
+            .collect::<Vec<_>>();
+
+        assert_eq!(failures,
+                   vec![("2".to_string(), 429, "rejected execution of bulk".to_string()),
+                        ("3".to_string(), 400, "failed to parse [titles.ja]".to_string()),
+                        ("4".to_string(), 400, "MapperParsingException[bad]".to_string())]);
+    }
+
+    #[test]
+    fn bulk_response_without_errors_has_no_failures() {
+        let response = json!({
+            "errors": false,
+            "items": [{"index": {"_id": "1", "status": 201}}]
+        });
+
+        assert!(bulk_failures(&response).is_empty());
+    }
+
+    #[test]
+    fn lists_every_failed_item() {
+        let response = serde_json::from_str::<JsValue>(MIXED_BULK_RESPONSE).unwrap();
+        let error = Error::from(ErrorKind::BulkItemsFailed(bulk_failures(&response)));
+
+        assert_eq!(error.to_string(),
+                   "3 documents in a bulk request failed:\n  \
+                    document 2 (status 429): rejected execution of bulk\n  \
+                    document 3 (status 400): failed to parse [titles.ja]\n  \
+                    document 4 (status 400): MapperParsingException[bad]");
+    }
+
+    #[test]
+    fn resends_only_retryable_items() {
+        let partial = r#"{"errors": true, "items": [
+            {"index": {"_id": "1", "status": 201}},
+            {"index": {"_id": "2", "status": 503, "error": {"reason": "unavailable"}}}
+        ]}"#;
+        let (url, server) = serve(vec![response(200, INFO),
+                                       response(200, partial),
+                                       response(200, r#"{"errors": false, "items": []}"#)]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        let actions = vec![("1".to_string(), "{\"index\":{\"_id\":\"1\"}}\n{}".to_string()),
+                           ("2".to_string(), "{\"index\":{\"_id\":\"2\"}}\n{}".to_string())];
+        client.send_bulk("series_1/_bulk", actions).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].contains("\"_id\":\"1\"") && requests[1].contains("\"_id\":\"2\""));
+        assert!(!requests[2].contains("\"_id\":\"1\"") && requests[2].contains("\"_id\":\"2\""));
+    }
+
+    #[test]
+    fn fails_on_non_retryable_items() {
+        let (url, server) = serve(vec![response(200, INFO), response(200, MIXED_BULK_RESPONSE)]);
+
+        let client = Client::new(&url, "series", "series", fast_retries(3)).unwrap();
+
+        let actions = vec![("1".to_string(), "{\"index\":{\"_id\":\"1\"}}\n{}".to_string())];
+
+        match *client.send_bulk("series_1/_bulk", actions).unwrap_err().kind() {
+            ErrorKind::BulkItemsFailed(ref failures) => assert_eq!(failures.len(), 3),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
 }
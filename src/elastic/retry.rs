@@ -0,0 +1,150 @@
+use hyper;
+use rand;
+use reqwest;
+use std::cmp;
+use std::io;
+use std::time::Duration;
+
+/// How `Client` retries requests that fail with a transient error: either a
+/// connection error or timeout (see `is_retryable_error`), or a response with
+/// one of the `retryable_statuses` (e.g. 429 when the bulk queue is full, or
+/// 503 during a cluster restart).
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first. 1 disables retries.
+    pub max_attempts: u32,
+    /// Backoff after the first failed attempt, doubling after each one
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub retryable_statuses: Vec<u16>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+            retryable_statuses: vec![429, 502, 503, 504],
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy that only makes one attempt, for requests that aren't safe to
+    /// repeat.
+    pub fn no_retries() -> Self {
+        RetryPolicy { max_attempts: 1, ..RetryPolicy::default() }
+    }
+
+    pub fn is_retryable(&self, status: u16) -> bool {
+        self.retryable_statuses.contains(&status)
+    }
+
+    /// How long to wait after the given failed attempt (starting from 1), or
+    /// `None` if there are no attempts left. Half of the backoff is random,
+    /// so that concurrent requests that failed together don't all retry at
+    /// the same time.
+    pub fn backoff(&self, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_attempts {
+            return None;
+        }
+
+        let exponent = cmp::min(attempt.saturating_sub(1), 31);
+        let backoff = cmp::min(millis(self.initial_backoff).saturating_mul(1 << exponent),
+                               millis(self.max_backoff));
+
+        let half = backoff / 2;
+        let jitter = rand::random::<u64>() % (backoff - half + 1);
+
+        Some(Duration::from_millis(half + jitter))
+    }
+}
+
+/// Whether a request that failed without a response is worth retrying. Only
+/// connection errors and timeouts are, since anything else (an invalid URL,
+/// too many redirects, etc) would just fail again.
+pub fn is_retryable_error(error: &reqwest::Error) -> bool {
+    let io_error = match *error {
+        reqwest::Error::Http(hyper::Error::Io(ref e)) => e,
+        _ => return false,
+    };
+
+    match io_error.kind() {
+        io::ErrorKind::ConnectionRefused |
+        io::ErrorKind::ConnectionReset |
+        io::ErrorKind::ConnectionAborted |
+        io::ErrorKind::NotConnected |
+        io::ErrorKind::BrokenPipe |
+        io::ErrorKind::TimedOut |
+        io::ErrorKind::WouldBlock => true,
+        _ => false,
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + (duration.subsec_nanos() / 1_000_000) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 4,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(300),
+            retryable_statuses: vec![503],
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_with_jitter() {
+        let policy = policy();
+
+        // Each backoff is between half of and the full exponential backoff
+        for &(attempt, full) in &[(1, 100), (2, 200), (3, 300)] {
+            for _ in 0..100 {
+                let backoff = millis(policy.backoff(attempt).unwrap());
+                assert!(backoff >= full / 2 && backoff <= full,
+                        "attempt {} backed off {}ms",
+                        attempt,
+                        backoff);
+            }
+        }
+
+        assert_eq!(policy.backoff(4), None);
+        assert_eq!(RetryPolicy::no_retries().backoff(1), None);
+    }
+
+    #[test]
+    fn retries_only_listed_statuses() {
+        let policy = policy();
+
+        assert!(policy.is_retryable(503));
+        assert!(!policy.is_retryable(429));
+        assert!(!policy.is_retryable(400));
+    }
+
+    #[test]
+    fn retries_connection_errors() {
+        // Nothing is listening once the listener is dropped
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let url = format!("http://127.0.0.1:{}/", port);
+
+        match reqwest::Client::new().unwrap().get(url.as_str()).send() {
+            Err(e) => assert!(is_retryable_error(&e), "{} shouldn't be retried", e),
+            Ok(_) => panic!("connected to a closed port"),
+        }
+    }
+
+    #[test]
+    fn doesnt_retry_redirect_loops() {
+        assert!(!is_retryable_error(&reqwest::Error::RedirectLoop));
+        assert!(!is_retryable_error(&reqwest::Error::TooManyRedirects));
+    }
+}
@@ -0,0 +1,160 @@
+use error::*;
+use serde_json::Value as JsValue;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Distribution {
+    Elasticsearch,
+    OpenSearch,
+}
+
+/// The search server's distribution and major version, which decide the
+/// mappings and endpoints used by `Client`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ServerVersion {
+    pub distribution: Distribution,
+    pub major: u32,
+}
+
+impl ServerVersion {
+    /// Reads the version from the server's `GET /` response, which looks like
+    /// `{"version": {"number": "7.10.2", "distribution": "opensearch"}}`
+    /// (`distribution` is only present for OpenSearch).
+    pub fn from_info(info: &JsValue) -> Result<Self> {
+        let version = info.get("version");
+
+        let distribution = version.and_then(|v| v.get("distribution")).and_then(|d| d.as_str());
+        let distribution = match distribution {
+            Some("opensearch") => Distribution::OpenSearch,
+            _ => Distribution::Elasticsearch,
+        };
+
+        let major = version.and_then(|v| v.get("number"))
+            .and_then(|n| n.as_str())
+            .and_then(|n| n.split('.').next())
+            .and_then(|major| major.parse::<u32>().ok());
+
+        match major {
+            Some(major) => {
+                Ok(ServerVersion {
+                    distribution: distribution,
+                    major: major,
+                })
+            }
+            None => Err(ErrorKind::InvalidServerVersion(info.to_string()).into()),
+        }
+    }
+
+    /// Whether indices have mapping types, which appear in the mappings and
+    /// in document endpoints like `{index}/{type}/_bulk` (Elasticsearch
+    /// before 7).
+    pub fn has_mapping_types(&self) -> bool {
+        self.distribution == Distribution::Elasticsearch && self.major < 7
+    }
+
+    /// Whether strings are mapped as `text`/`keyword` rather than `string`
+    /// (Elasticsearch 5 and later).
+    pub fn has_text_fields(&self) -> bool {
+        self.distribution == Distribution::OpenSearch || self.major >= 5
+    }
+
+    /// Whether the `_all` field exists, and should be disabled (Elasticsearch
+    /// before 6).
+    pub fn has_all_field(&self) -> bool {
+        self.distribution == Distribution::Elasticsearch && self.major < 6
+    }
+}
+
+impl fmt::Display for ServerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.distribution {
+            Distribution::Elasticsearch => "Elasticsearch",
+            Distribution::OpenSearch => "OpenSearch",
+        };
+
+        write!(f, "{} {}", name, self.major)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(info: JsValue) -> ServerVersion {
+        ServerVersion::from_info(&info).unwrap()
+    }
+
+    fn elasticsearch(major: u32) -> ServerVersion {
+        ServerVersion {
+            distribution: Distribution::Elasticsearch,
+            major: major,
+        }
+    }
+
+    #[test]
+    fn reads_elasticsearch_versions() {
+        // Trimmed down `GET /` responses
+        let es5 = json!({
+            "name": "node-1",
+            "cluster_name": "elasticsearch",
+            "version": { "number": "5.6.16", "lucene_version": "6.6.1" },
+            "tagline": "You Know, for Search"
+        });
+        let es6 = json!({ "version": { "number": "6.8.23", "build_flavor": "default" } });
+        let es7 = json!({ "version": { "number": "7.17.9", "build_flavor": "default" } });
+        let es8 = json!({ "version": { "number": "8.11.1", "build_flavor": "default" } });
+
+        assert_eq!(version(es5), elasticsearch(5));
+        assert_eq!(version(es6), elasticsearch(6));
+        assert_eq!(version(es7), elasticsearch(7));
+        assert_eq!(version(es8), elasticsearch(8));
+    }
+
+    #[test]
+    fn reads_opensearch_versions() {
+        let info = json!({
+            "name": "opensearch-node1",
+            "version": {
+                "distribution": "opensearch",
+                "number": "2.11.0",
+                "minimum_wire_compatibility_version": "7.10.0"
+            },
+            "tagline": "The OpenSearch Project: https://opensearch.org/"
+        });
+
+        assert_eq!(version(info),
+                   ServerVersion {
+                       distribution: Distribution::OpenSearch,
+                       major: 2,
+                   });
+    }
+
+    #[test]
+    fn rejects_info_without_version() {
+        assert!(ServerVersion::from_info(&json!({ "name": "node-1" })).is_err());
+        assert!(ServerVersion::from_info(&json!({ "version": { "number": "x.y" } })).is_err());
+    }
+
+    #[test]
+    fn decides_features_by_version() {
+        let opensearch = ServerVersion {
+            distribution: Distribution::OpenSearch,
+            major: 1,
+        };
+
+        assert!(elasticsearch(6).has_mapping_types());
+        assert!(!elasticsearch(7).has_mapping_types());
+        assert!(!opensearch.has_mapping_types());
+
+        assert!(!elasticsearch(2).has_text_fields());
+        assert!(elasticsearch(5).has_text_fields());
+        assert!(opensearch.has_text_fields());
+
+        assert!(elasticsearch(5).has_all_field());
+        assert!(!elasticsearch(6).has_all_field());
+        assert!(!opensearch.has_all_field());
+
+        assert_eq!(elasticsearch(7).to_string(), "Elasticsearch 7");
+        assert_eq!(opensearch.to_string(), "OpenSearch 1");
+    }
+}
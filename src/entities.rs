@@ -0,0 +1,194 @@
+use std::borrow::Cow;
+use std::char;
+
+// Longest entity name we recognize, excluding the leading '&' and trailing ';'
+const MAX_ENTITY_LEN: usize = 8;
+
+/// Decodes HTML entities. Handles the named entities that show up in
+/// AniDB's dump as well as decimal (`&#039;`) and hex (`&#x27;`) numeric
+/// entities. Anything that isn't a recognized entity is left as-is, as are
+/// numeric entities for control characters (e.g. `&#0;`), which have no
+/// place in a title.
+///
+/// Returns the input unchanged (without allocating) if it has no entities.
+pub fn decode(s: &str) -> Cow<str> {
+    if !s.contains('&') {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        let decoded = rest[1..]
+            .bytes()
+            .take(MAX_ENTITY_LEN + 1)
+            .position(|b| b == b';')
+            .and_then(|end| entity_to_char(&rest[1..end + 1]).map(|c| (c, end + 2)));
+
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+/// Escapes a title the way AniDB does in its dumps, so that `decode` gives
+/// back the original string.
+///
+/// Returns the input unchanged (without allocating) if nothing needs escaping.
+pub fn encode(s: &str) -> Cow<str> {
+    if !s.contains(is_escaped) {
+        return Cow::Borrowed(s);
+    }
+
+    let mut out = String::with_capacity(s.len() + 8);
+
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#039;"),
+            _ => out.push(c),
+        }
+    }
+
+    Cow::Owned(out)
+}
+
+fn is_escaped(c: char) -> bool {
+    match c {
+        '&' | '<' | '>' | '"' | '\'' => true,
+        _ => false,
+    }
+}
+
+fn entity_to_char(entity: &str) -> Option<char> {
+    if entity.starts_with('#') {
+        let num = &entity[1..];
+
+        let (digits, radix) = if num.starts_with('x') || num.starts_with('X') {
+            (&num[1..], 16)
+        } else {
+            (num, 10)
+        };
+
+        // `from_str_radix` would also accept a leading '+'
+        if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+            return None;
+        }
+
+        return u32::from_str_radix(digits, radix)
+            .ok()
+            .and_then(char::from_u32)
+            .and_then(|c| if c.is_control() { None } else { Some(c) });
+    }
+
+    let c = match entity {
+        "amp" => '&',
+        "lt" => '<',
+        "gt" => '>',
+        "quot" => '"',
+        "apos" => '\'',
+        "nbsp" => '\u{a0}',
+        "hellip" => '…',
+        "ndash" => '–',
+        "mdash" => '—',
+        "lsquo" => '‘',
+        "rsquo" => '’',
+        "ldquo" => '“',
+        "rdquo" => '”',
+        "times" => '×',
+        "frac12" => '½',
+        "deg" => '°',
+        "hearts" => '♥',
+        "star" => '☆',
+        _ => return None,
+    };
+
+    Some(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_named_entities() {
+        assert_eq!(decode("Tom &amp; Jerry"), "Tom & Jerry");
+        assert_eq!(decode("&lt;&gt;&quot;&apos;"), "<>\"'");
+        assert_eq!(decode("Love&hearts;Live&hellip;"), "Love♥Live…");
+        assert_eq!(decode("&frac12;&times;&deg;&star;"), "½×°☆");
+    }
+
+    #[test]
+    fn decodes_numeric_entities() {
+        assert_eq!(decode("Kiki&#039;s"), "Kiki's");
+        assert_eq!(decode("&#12354;"), "あ");
+        assert_eq!(decode("Kiki&#x27;s"), "Kiki's");
+        assert_eq!(decode("&#X3042;&#x1F600;"), "あ😀");
+    }
+
+    #[test]
+    fn leaves_unknown_entities() {
+        assert_eq!(decode("&foo; &AMP;"), "&foo; &AMP;");
+        assert_eq!(decode("&verylongname;"), "&verylongname;");
+        assert_eq!(decode("&#; &#x; &#12a; &#xZZ; &#+39; &#-1;"),
+                   "&#; &#x; &#12a; &#xZZ; &#+39; &#-1;");
+    }
+
+    #[test]
+    fn leaves_unterminated_entities() {
+        assert_eq!(decode("Tom &amp Jerry;"), "Tom &amp Jerry;");
+        assert_eq!(decode("&amp&lt;"), "&amp<");
+        assert_eq!(decode("trailing &"), "trailing &");
+        assert_eq!(decode("&#039"), "&#039");
+    }
+
+    #[test]
+    fn leaves_out_of_range_and_control_characters() {
+        // Past the last code point (in hex and decimal), and a surrogate
+        assert_eq!(decode("&#x110000; &#1114112; &#xD800;"),
+                   "&#x110000; &#1114112; &#xD800;");
+
+        assert_eq!(decode("&#0;&#x0;&#10;&#x1F;&#127;&#x9F;"),
+                   "&#0;&#x0;&#10;&#x1F;&#127;&#x9F;");
+    }
+
+    #[test]
+    fn borrows_when_unchanged() {
+        match decode("Seikai no Monshou") {
+            Cow::Borrowed(s) => assert_eq!(s, "Seikai no Monshou"),
+            Cow::Owned(_) => panic!("expected a borrowed string"),
+        }
+
+        match encode("星界の紋章") {
+            Cow::Borrowed(s) => assert_eq!(s, "星界の紋章"),
+            Cow::Owned(_) => panic!("expected a borrowed string"),
+        }
+    }
+
+    #[test]
+    fn encodes_like_anidb() {
+        assert_eq!(encode("Kiki's <Delivery> & \"Service\""),
+                   "Kiki&#039;s &lt;Delivery&gt; &amp; &quot;Service&quot;");
+
+        for title in &["Tom & Jerry", "&amp;", "<&#039;>", "Love♥Live"] {
+            assert_eq!(decode(&encode(title)), *title);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use clubdarn;
+use elastic::BulkItemFailure;
 use reqwest;
 use serde_json;
+use serde_yaml;
 use std;
+use xml_rs;
 
 error_chain! {
     errors {
@@ -17,6 +20,68 @@ error_chain! {
             description("failed to parse line from file")
             display("failed to parse line {}", line_number)
         }
+        UnwritableTitleType(title_type: String) {
+            description("title type can't be written to a .dat dump")
+            display("title type \"{}\" has no ID in the .dat format", title_type)
+        }
+        InvalidLine(line_number: u32, line: String) {
+            description("invalid line in titles dump")
+            display("invalid line {}: {}", line_number, line)
+        }
+        TooManyBadLines(bad: usize, total: usize, max_ratio: f64) {
+            description("too many invalid lines in titles dump")
+            display("{} of {} lines failed to parse, exceeding the maximum ratio of {}",
+                    bad,
+                    total,
+                    max_ratio)
+        }
+        UnsortedTitles(id: u32, previous_id: u32) {
+            description("titles dump is not sorted by anime ID")
+            display("found anime ID {} after {}, but titles are expected to be sorted by ID \
+                     (use --unsorted for unsorted input)",
+                    id,
+                    previous_id)
+        }
+        InvalidDumpFormat(format: String) {
+            description("invalid dump format")
+            display("unknown dump format \"{}\" (expected \"dat\" or \"xml\")", format)
+        }
+        InvalidOverride(line_number: u32, reason: String) {
+            description("invalid override")
+            display("invalid override on line {}: {}", line_number, reason)
+        }
+        UnknownOverrideIds(ids: Vec<u32>) {
+            description("overrides reference AniDB IDs that don't exist")
+            display("overrides reference AniDB IDs not found in the titles dump: {:?}", ids)
+        }
+        InvalidGoldPair(line_number: u32, reason: String) {
+            description("invalid gold standard pair")
+            display("invalid gold standard pair on line {}: {}", line_number, reason)
+        }
+        InvalidConflictPolicy(policy: String) {
+            description("invalid conflict policy")
+            display("unknown conflict policy \"{}\" (expected \"keep-all\", \"keep-best\" or \
+                     \"defer\")",
+                    policy)
+        }
+        InvalidThresholds(reason: String) {
+            description("invalid match thresholds")
+            display("invalid match thresholds: {}", reason)
+        }
+        BulkItemsFailed(failures: Vec<BulkItemFailure>) {
+            description("documents in a bulk request failed")
+            display("{} documents in a bulk request failed:{}",
+                    failures.len(),
+                    failures.iter().map(|f| format!("\n  {}", f)).collect::<String>())
+        }
+        InvalidIndexConfig(reason: String) {
+            description("invalid index settings and mappings")
+            display("invalid index settings and mappings: {}", reason)
+        }
+        InvalidServerVersion(info: String) {
+            description("failed to detect search server version")
+            display("failed to detect search server version from {}", info)
+        }
         InvalidUrl(url: String) {
             description("failed to parse URL")
             display("failed to parse URL {}", url)
@@ -31,6 +96,8 @@ error_chain! {
         Io(std::io::Error);
         Http(reqwest::Error);
         Json(serde_json::Error);
+        Yaml(serde_yaml::Error);
         ClubDarn(clubdarn::Error);
+        Xml(xml_rs::reader::Error);
     }
 }
@@ -0,0 +1,321 @@
+use csv;
+use error::*;
+use serde_json;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// A ClubDAM title with the AniDB ID it should be matched to, or `None` if it
+/// shouldn't be matched at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoldPair {
+    pub clubdam_title: String,
+    pub anidb_id: Option<u32>,
+}
+
+/// Reads gold standard pairs from a CSV file with a header row, e.g.:
+///
+/// ```text
+/// clubdam_title,anidb_id
+/// アイカツ,9348
+/// 進撃の巨人 Season2,12345
+/// テスト,
+/// ```
+pub fn read_gold<P>(path: P) -> Result<Vec<GoldPair>>
+    where P: AsRef<Path>
+{
+    let path = path.as_ref();
+    let reader = csv::Reader::from_file(path)
+        .chain_err(|| format!("failed to open gold standard file {}", path.display()))?;
+
+    read_gold_csv(reader)
+}
+
+/// Like `read_gold`, but from any reader.
+pub fn read_gold_from_reader<R>(reader: R) -> Result<Vec<GoldPair>>
+    where R: Read
+{
+    read_gold_csv(csv::Reader::from_reader(reader))
+}
+
+fn read_gold_csv<R>(reader: csv::Reader<R>) -> Result<Vec<GoldPair>>
+    where R: Read
+{
+    let mut reader = reader.has_headers(true).flexible(true);
+    let mut pairs = Vec::new();
+
+    for (i, record) in reader.records().enumerate() {
+        // Line numbers are 1-based, after the header
+        let line_num = i as u32 + 2;
+
+        let invalid = |reason: String| -> Error {
+            ErrorKind::InvalidGoldPair(line_num, reason).into()
+        };
+
+        let row = record.chain_err(|| invalid("unreadable row".to_string()))?;
+        let field = |n: usize| row.get(n).map(|f| f.trim()).unwrap_or("");
+
+        let title = field(0);
+        if title.is_empty() {
+            return Err(invalid("missing ClubDAM title".to_string()));
+        }
+
+        let anidb_id = match field(1) {
+            "" => None,
+            id => {
+                match id.parse::<u32>() {
+                    Ok(id) => Some(id),
+                    Err(_) => return Err(invalid(format!("invalid AniDB ID \"{}\"", id))),
+                }
+            }
+        };
+
+        pairs.push(GoldPair {
+            clubdam_title: title.to_string(),
+            anidb_id: anidb_id,
+        });
+    }
+
+    Ok(pairs)
+}
+
+/// The matcher's result for a gold standard title.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Prediction {
+    pub clubdam_title: String,
+    pub expected: Option<u32>,
+    pub predicted: Option<u32>,
+}
+
+impl Prediction {
+    pub fn is_correct(&self) -> bool {
+        self.expected == self.predicted
+    }
+}
+
+/// Match quality against a gold standard. Precision is the share of
+/// predicted matches that are correct, and recall is the share of expected
+/// matches that were predicted correctly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Evaluation {
+    pub precision: f64,
+    pub recall: f64,
+    pub predictions: Vec<Prediction>,
+}
+
+impl Evaluation {
+    /// Evaluates `predicted`, a map of ClubDAM titles to the AniDB IDs they
+    /// were matched to. Gold titles missing from it count as unmatched.
+    pub fn new(gold: &[GoldPair], predicted: &HashMap<String, u32>) -> Self {
+        let predictions = gold.iter()
+            .map(|pair| {
+                Prediction {
+                    clubdam_title: pair.clubdam_title.clone(),
+                    expected: pair.anidb_id,
+                    predicted: predicted.get(&pair.clubdam_title).cloned(),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let correct = predictions.iter()
+            .filter(|p| p.predicted.is_some() && p.is_correct())
+            .count();
+        let predicted_count = predictions.iter().filter(|p| p.predicted.is_some()).count();
+        let expected_count = predictions.iter().filter(|p| p.expected.is_some()).count();
+
+        Evaluation {
+            precision: ratio(correct, predicted_count),
+            recall: ratio(correct, expected_count),
+            predictions: predictions,
+        }
+    }
+
+    pub fn from_file<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .chain_err(|| format!("failed to open evaluation {}", path.display()))?;
+
+        Ok(serde_json::from_reader(file)?)
+    }
+
+    pub fn write_json<P>(&self, path: P) -> Result<()>
+        where P: AsRef<Path>
+    {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+
+    /// Predictions that were correct in `previous`, but aren't anymore.
+    pub fn regressions<'a>(&'a self, previous: &Evaluation) -> Vec<&'a Prediction> {
+        let previously_correct = previous.predictions
+            .iter()
+            .filter(|p| p.is_correct())
+            .map(|p| p.clubdam_title.as_str())
+            .collect::<HashSet<&str>>();
+
+        self.predictions
+            .iter()
+            .filter(|p| !p.is_correct() && previously_correct.contains(p.clubdam_title.as_str()))
+            .collect()
+    }
+}
+
+// Ratio of `n` to `total`, or 0 if `total` is 0
+fn ratio(n: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        n as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn gold(pairs: &[(&str, Option<u32>)]) -> Vec<GoldPair> {
+        pairs.iter()
+            .map(|&(title, id)| {
+                GoldPair {
+                    clubdam_title: title.to_string(),
+                    anidb_id: id,
+                }
+            })
+            .collect()
+    }
+
+    fn predicted(pairs: &[(&str, u32)]) -> HashMap<String, u32> {
+        pairs.iter().map(|&(title, id)| (title.to_string(), id)).collect()
+    }
+
+    fn read(input: &'static str) -> Result<Vec<GoldPair>> {
+        read_gold_from_reader(Cursor::new(input.as_bytes()))
+    }
+
+    fn gold_set() -> Vec<GoldPair> {
+        gold(&[("アイカツ", Some(9348)),
+               ("けいおん!", Some(6257)),
+               ("進撃の巨人", Some(9541)),
+               ("ラブライブ!", Some(9138)),
+               ("星界の紋章", Some(1)),
+               ("テスト", None),
+               ("カラオケ", None)])
+    }
+
+    #[test]
+    fn measures_precision_and_recall() {
+        let predictions = predicted(&[// Correct
+                                      ("アイカツ", 9348),
+                                      ("けいおん!", 6257),
+                                      // Wrong
+                                      ("進撃の巨人", 1),
+                                      // Extra: shouldn't have matched
+                                      ("テスト", 22),
+                                      // Not in the gold standard, so ignored
+                                      ("ドラえもん", 2)]);
+
+        // "ラブライブ!" and "星界の紋章" are missing, and "カラオケ" is
+        // correctly unmatched
+        let evaluation = Evaluation::new(&gold_set(), &predictions);
+
+        // 2 of 4 predicted matches are correct, out of 5 expected
+        assert_eq!(evaluation.precision, 0.5);
+        assert_eq!(evaluation.recall, 0.4);
+
+        let outcomes = evaluation.predictions
+            .iter()
+            .map(|p| (p.clubdam_title.as_str(), p.predicted, p.is_correct()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(outcomes,
+                   vec![("アイカツ", Some(9348), true),
+                        ("けいおん!", Some(6257), true),
+                        ("進撃の巨人", Some(1), false),
+                        ("ラブライブ!", None, false),
+                        ("星界の紋章", None, false),
+                        ("テスト", Some(22), false),
+                        ("カラオケ", None, true)]);
+    }
+
+    #[test]
+    fn scores_zero_without_matches() {
+        let evaluation = Evaluation::new(&gold_set(), &HashMap::new());
+        assert_eq!(evaluation.precision, 0.0);
+        assert_eq!(evaluation.recall, 0.0);
+
+        let evaluation = Evaluation::new(&[], &predicted(&[("アイカツ", 9348)]));
+        assert_eq!(evaluation.precision, 0.0);
+        assert_eq!(evaluation.recall, 0.0);
+    }
+
+    #[test]
+    fn finds_regressions() {
+        let previous = Evaluation::new(&gold_set(),
+                                       &predicted(&[("アイカツ", 9348),
+                                                    ("けいおん!", 6257),
+                                                    ("進撃の巨人", 9541),
+                                                    ("テスト", 22)]));
+
+        let current = Evaluation::new(&gold_set(),
+                                      &predicted(&[// Still correct
+                                                   ("アイカツ", 9348),
+                                                   // Now wrong, and "けいおん!" is now missing
+                                                   ("進撃の巨人", 1),
+                                                   // Was wrong already
+                                                   ("テスト", 33),
+                                                   // Was missing, now wrong
+                                                   ("星界の紋章", 2),
+                                                   // Was unmatched, now extra
+                                                   ("カラオケ", 4)]));
+
+        let regressions = current.regressions(&previous)
+            .into_iter()
+            .map(|p| p.clubdam_title.as_str())
+            .collect::<Vec<_>>();
+
+        assert_eq!(regressions, vec!["けいおん!", "進撃の巨人", "カラオケ"]);
+        assert!(previous.regressions(&previous).is_empty());
+    }
+
+    #[test]
+    fn reads_gold_pairs() {
+        let input = "clubdam_title,anidb_id\n\
+                     アイカツ,9348\n\
+                     \" 進撃の巨人 Season2 \", 12345 \n\
+                     テスト,\n\
+                     カラオケ\n";
+
+        assert_eq!(read(input).unwrap(),
+                   gold(&[("アイカツ", Some(9348)),
+                          ("進撃の巨人 Season2", Some(12345)),
+                          ("テスト", None),
+                          ("カラオケ", None)]));
+    }
+
+    fn invalid_line(result: Result<Vec<GoldPair>>) -> (u32, String) {
+        match *result.unwrap_err().kind() {
+            ErrorKind::InvalidGoldPair(line_num, ref reason) => (line_num, reason.clone()),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_rows() {
+        let missing_title = "clubdam_title,anidb_id\nアイカツ,9348\n,9541\n";
+        assert_eq!(invalid_line(read(missing_title)),
+                   (3, "missing ClubDAM title".to_string()));
+
+        let bad_id = "clubdam_title,anidb_id\nアイカツ,abc\n";
+        assert_eq!(invalid_line(read(bad_id)),
+                   (2, "invalid AniDB ID \"abc\"".to_string()));
+
+        let negative_id = "clubdam_title,anidb_id\nアイカツ,9348\nテスト,-1\n";
+        assert_eq!(invalid_line(read(negative_id)),
+                   (3, "invalid AniDB ID \"-1\"".to_string()));
+    }
+}
@@ -7,67 +7,322 @@ extern crate serde_derive;
 extern crate serde;
 #[macro_use]
 extern crate serde_json;
+extern crate serde_yaml;
 extern crate reqwest;
+extern crate hyper;
 extern crate time;
 extern crate itertools;
 extern crate clubdarn;
+extern crate flate2;
+extern crate xml as xml_rs;
+extern crate unicode_normalization;
+extern crate crossbeam;
+extern crate rand;
 
 pub mod error;
 use csv::NextField;
 pub use error::*;
+use flate2::read::GzDecoder;
 use std::fs::File;
-use std::io::{BufReader, BufRead};
+use std::io;
+use std::io::{BufReader, BufRead, Cursor, Read};
 use std::path::Path;
+use serde::{Serialize, Serializer};
+use std::str::FromStr;
+pub mod anime;
+pub mod conflicts;
+pub mod diff;
 pub mod elastic;
+pub mod entities;
+pub mod evaluate;
+pub mod matcher;
+pub mod normalize;
+pub mod overrides;
+pub mod parse_mode;
+pub mod report;
+pub mod romaji;
+pub mod sequel;
+pub mod writer;
+pub mod xml;
+pub use anime::{AnimeEntry, group_by_anime, group_by_anime_unsorted};
+pub use parse_mode::{BadLine, BadLineReason, CheckedTitles, ParseMode, ParseReport};
+pub use writer::TitleWriter;
+pub use xml::XmlTitleIterator;
 
-// Sorted by lowest priority to highest
-#[derive(Debug, PartialEq, Clone, Copy)]
-#[repr(u8)]
+
+/// The kind of title, as given by AniDB. Priority between types (e.g. for
+/// picking a series' main title) is decided by `TitlePriority`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
 pub enum TitleType {
-    Short = 0,
-    Synonym = 1,
-    Official = 2,
-    Primary = 3,
+    Primary,
+    Synonym,
+    Short,
+    Official,
+    /// Kana reading of a Japanese title (XML dump only)
+    Kana,
+    /// Title as written on a title card (XML dump only)
+    Card,
+    /// A type AniDB added that we don't know about yet, holding its raw ID
+    /// (`.dat` dump) or name (XML dump)
+    Unknown(String),
 }
 
 impl TitleType {
-    fn from_id(id: &str) -> Result<Self> {
+    fn from_id(id: &str) -> Self {
         use TitleType::*;
         match id {
-            "1" => Ok(Primary),
-            "2" => Ok(Synonym),
-            "3" => Ok(Short),
-            "4" => Ok(Official),
-            _ => Err(ErrorKind::InvalidTitleType(id.to_string()).into()),
+            "1" => Primary,
+            "2" => Synonym,
+            "3" => Short,
+            "4" => Official,
+            _ => Unknown(id.to_string()),
+        }
+    }
+
+    // Title types in the XML dump are names rather than numeric IDs
+    fn from_xml_name(name: &str) -> Self {
+        use TitleType::*;
+        match name {
+            "main" => Primary,
+            "syn" => Synonym,
+            "short" => Short,
+            "official" => Official,
+            "kana" => Kana,
+            "card" => Card,
+            _ => Unknown(name.to_string()),
+        }
+    }
+
+    /// The numeric ID used for this type in AniDB's `.dat` dump, if it has one.
+    pub fn id(&self) -> Option<&str> {
+        use TitleType::*;
+        match *self {
+            Primary => Some("1"),
+            Synonym => Some("2"),
+            Short => Some("3"),
+            Official => Some("4"),
+            Kana | Card => None,
+            Unknown(ref id) => {
+                // Types that came from the XML dump have names, not IDs
+                if id.chars().all(|c| c.is_digit(10)) {
+                    Some(id)
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    /// The name used for this type in AniDB's XML dump.
+    pub fn name(&self) -> &str {
+        use TitleType::*;
+        match *self {
+            Primary => "main",
+            Synonym => "syn",
+            Short => "short",
+            Official => "official",
+            Kana => "kana",
+            Card => "card",
+            Unknown(ref name) => name,
         }
     }
 }
 
+impl Serialize for TitleType {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+        where S: Serializer
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl FromStr for TitleType {
+    type Err = Error;
+
+    /// Parses the names used in the XML dump ("main", "syn", etc). Unlike
+    /// when parsing dumps, unrecognized names are an error rather than
+    /// `Unknown`, since they're most likely typos.
+    fn from_str(s: &str) -> Result<Self> {
+        match TitleType::from_xml_name(s) {
+            TitleType::Unknown(_) => Err(ErrorKind::InvalidTitleType(s.to_string()).into()),
+            t => Ok(t),
+        }
+    }
+}
+
+/// Order of preference between title types, highest priority first. Types
+/// not in the list (including `TitleType::Unknown`) rank below all others.
+#[derive(Debug, Clone)]
+pub struct TitlePriority(pub Vec<TitleType>);
+
+impl TitlePriority {
+    pub fn rank(&self, title_type: &TitleType) -> usize {
+        self.0.iter().position(|t| t == title_type).unwrap_or(self.0.len())
+    }
+}
+
+impl Default for TitlePriority {
+    fn default() -> Self {
+        use TitleType::*;
+        TitlePriority(vec![Primary, Official, Synonym, Short, Kana, Card])
+    }
+}
+
+impl FromStr for TitlePriority {
+    type Err = Error;
+
+    /// Parses a comma-separated list of type names, e.g. "main,official,syn"
+    fn from_str(s: &str) -> Result<Self> {
+        s.split(',')
+            .map(|name| name.trim().parse::<TitleType>())
+            .collect::<Result<Vec<_>>>()
+            .map(TitlePriority)
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Title {
     pub id: u32,
     pub title_type: TitleType,
     pub language: String,
     pub title: String,
+    /// The title exactly as it appeared in the `.dat` dump, before decoding
+    /// entities. Kept so that `TitleWriter` can write it back unchanged, since
+    /// the dump isn't consistent about which characters are escaped.
+    pub raw_title: Option<String>,
+}
+
+/// Which of AniDB's title dumps is being read.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum DumpFormat {
+    /// `anime-titles.dat`, parsed by `TitleIterator`
+    Dat,
+    /// `anime-titles.xml`, parsed by `XmlTitleIterator`
+    Xml,
+}
+
+impl FromStr for DumpFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "dat" => Ok(DumpFormat::Dat),
+            "xml" => Ok(DumpFormat::Xml),
+            _ => Err(ErrorKind::InvalidDumpFormat(s.to_string()).into()),
+        }
+    }
+}
+
+// First two bytes of any gzip stream
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Wraps the source in a gzip decoder if it starts with the gzip magic bytes
+fn decompress<R>(mut source: R) -> Result<Box<Read>>
+    where R: Read + 'static
+{
+    // A single read can return just one byte (e.g. from a pipe), so keep
+    // reading until there are enough to check, or the input ends
+    let mut magic = [0; 2];
+    let mut len = 0;
+    while len < magic.len() {
+        match source.read(&mut magic[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let is_gzip = magic[..len] == GZIP_MAGIC;
+    let buffered = BufReader::new(Cursor::new(magic[..len].to_vec()).chain(source));
+
+    if is_gzip {
+        Ok(Box::new(GzDecoder::new(buffered)?))
+    } else {
+        Ok(Box::new(buffered))
+    }
+}
+
+/// Information from the comment lines at the top of the `.dat` dump, e.g.:
+///
+/// ```text
+/// # created: Fri Mar 10 02:00:01 2017
+/// # <aid>|<type>|<language>|<title>
+/// # type: 1=primary title (one per anime), 2=synonyms (multiple per anime), ...
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DumpMetadata {
+    /// Header comment lines, without the leading "#"
+    pub comments: Vec<String>,
+    /// Header comment lines exactly as they appeared, without line endings
+    pub raw_comments: Vec<String>,
+    /// When AniDB generated the dump, if the header says
+    pub created: Option<time::Tm>,
+}
+
+impl DumpMetadata {
+    fn from_raw_comments(raw_comments: Vec<String>) -> Self {
+        let comments = raw_comments.iter()
+            .map(|c| c[1..].trim().to_string())
+            .collect::<Vec<_>>();
+
+        let created = comments.iter()
+            .filter(|c| c.starts_with("created:"))
+            .next()
+            .and_then(|c| {
+                // Collapse padding like "Mar  9" so it parses with "%d"
+                let date = c["created:".len()..].split_whitespace().collect::<Vec<_>>().join(" ");
+                time::strptime(&date, "%a %b %d %H:%M:%S %Y").ok()
+            });
+
+        DumpMetadata {
+            comments: comments,
+            raw_comments: raw_comments,
+            created: created,
+        }
+    }
 }
 
 pub struct TitleIterator {
-    reader: csv::Reader<File>,
+    reader: csv::Reader<Box<Read>>,
     line_num: u32,
+    metadata: DumpMetadata,
+    // Fields of the record currently being parsed, joined by '|'
+    raw: String,
+    end_of_record: bool,
 }
 
 impl TitleIterator {
+    /// Opens an AniDB titles dump, which may be either plain text
+    /// (`anime-titles.dat`) or gzipped (`anime-titles.dat.gz`). Compression
+    /// is detected from the file's magic bytes rather than its extension.
     pub fn new<P>(file_path: P) -> Result<TitleIterator>
         where P: AsRef<Path>
     {
         let file = File::open(file_path)?;
-        let mut reader = BufReader::with_capacity(1, file);
+        TitleIterator::from_reader(file)
+    }
+
+    /// Parses an AniDB titles dump from any reader (stdin, an in-memory
+    /// buffer, a network stream, etc). As with `new`, gzipped input is
+    /// decompressed transparently.
+    pub fn from_reader<R>(source: R) -> Result<TitleIterator>
+        where R: Read + 'static
+    {
+        // Capacity of 1 so that no data past the header is left behind in the
+        // buffer when we unwrap it below
+        let mut reader = BufReader::with_capacity(1, decompress(source)?);
 
-        // Ignore first 3 lines, which are comments
-        {
+        // First 3 lines are comments
+        let mut comments = Vec::with_capacity(3);
+        for _ in 0..3 {
             let mut s = String::new();
-            for _ in 0..3 {
-                let _ = reader.read_line(&mut s);
+            let _ = reader.read_line(&mut s);
+
+            if s.starts_with('#') {
+                let len = s.trim_right_matches(|c| c == '\n' || c == '\r').len();
+                s.truncate(len);
+                comments.push(s);
             }
         }
         let line_num: u32 = 3;
@@ -81,76 +336,327 @@ impl TitleIterator {
         Ok(TitleIterator {
             reader: csv_reader,
             line_num: line_num,
+            metadata: DumpMetadata::from_raw_comments(comments),
+            raw: String::new(),
+            end_of_record: false,
         })
     }
-}
 
-fn fail_parse<T>(line_num: u32) -> Option<Result<T>> {
-    Some(Err(ErrorKind::InvalidParse(line_num).into()))
-}
+    pub fn metadata(&self) -> &DumpMetadata {
+        &self.metadata
+    }
 
-impl Iterator for TitleIterator {
-    type Item = Result<Title>;
+    // Reads the next field, keeping track of the raw line for error reporting
+    fn next_field(&mut self) -> NextField<str> {
+        let field = self.reader.next_str();
 
-    fn next(&mut self) -> Option<Self::Item> {
-        let id: u32 = match self.reader.next_str() {
-            NextField::Data(s) => {
-                match s.parse::<u32>() {
-                    Ok(id) => id,
-                    Err(_) => {
-                        let kind = ErrorKind::InvalidId(s.to_string(), self.line_num);
-                        return Some(Err(kind.into()));
-                    }
-                }
+        if let NextField::Data(s) = field {
+            if !self.raw.is_empty() {
+                self.raw.push('|');
             }
-            NextField::EndOfCsv => return None,
-            _ => return fail_parse(self.line_num),
-        };
+            self.raw.push_str(s);
+        } else {
+            self.end_of_record = true;
+        }
 
-        let title_type = match self.reader.next_str() {
+        field
+    }
+
+    fn skip_record(&mut self) {
+        while !self.end_of_record {
+            let _ = self.next_field();
+        }
+
+        self.line_num += 1;
+    }
+
+    fn parse_record(&mut self) -> Result<Option<Title>> {
+        let line_num = self.line_num;
+
+        let id: u32 = match self.next_field() {
             NextField::Data(s) => {
-                match TitleType::from_id(s) {
-                    Ok(t) => t,
-                    Err(e) => return Some(Err(e)),
-                }
+                s.parse::<u32>().map_err(|_| ErrorKind::InvalidId(s.to_string(), line_num))?
             }
-            _ => return fail_parse(self.line_num),
+            NextField::EndOfCsv => return Ok(None),
+            _ => return Err(ErrorKind::InvalidParse(line_num).into()),
+        };
+
+        let title_type = match self.next_field() {
+            NextField::Data(s) => TitleType::from_id(s),
+            _ => return Err(ErrorKind::InvalidParse(line_num).into()),
         };
 
-        let language = match self.reader.next_str() {
+        let language = match self.next_field() {
             NextField::Data(s) => s.to_string(),
-            _ => return fail_parse(self.line_num),
+            _ => return Err(ErrorKind::InvalidParse(line_num).into()),
         };
 
-        let mut title = match self.reader.next_str() {
-            NextField::Data(s) => {
-                // This string replace slows things down a lot
-                s.replace("&lt;", "<").replace("&gt;", ">")
-            }
-            _ => return fail_parse(self.line_num),
+        let mut raw_title = match self.next_field() {
+            NextField::Data(s) => s.to_string(),
+            _ => return Err(ErrorKind::InvalidParse(line_num).into()),
         };
 
         loop {
-            match self.reader.next_str() {
+            match self.next_field() {
                 NextField::EndOfRecord => break,
 
                 // "Shin Evangelion Gekijouban:||" has "||" at the end, heck
                 NextField::Data(s) => {
-                    title.push('|');
-                    title.push_str(s);
+                    raw_title.push('|');
+                    raw_title.push_str(s);
                 }
 
-                _ => return fail_parse(self.line_num),
+                _ => return Err(ErrorKind::InvalidParse(line_num).into()),
             }
         }
 
+        let title = entities::decode(&raw_title).into_owned();
+
         self.line_num += 1;
 
-        Some(Ok(Title {
+        Ok(Some(Title {
             id: id,
             title_type: title_type,
             language: language,
             title: title,
+            raw_title: Some(raw_title),
         }))
     }
 }
+
+impl Iterator for TitleIterator {
+    type Item = Result<Title>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.raw.clear();
+        self.end_of_record = false;
+
+        match self.parse_record() {
+            Ok(Some(title)) => Some(Ok(title)),
+            Ok(None) => None,
+            Err(e) => {
+                // Skip the rest of the bad line so the next call starts fresh
+                let line_num = self.line_num;
+                self.skip_record();
+
+                let raw = self.raw.clone();
+                Some(Err(e).chain_err(|| ErrorKind::InvalidLine(line_num, raw)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+    use std::io::Cursor;
+
+    const DUMP: &'static [u8] = b"# created: Fri Mar 10 02:00:01 2017\n\
+# <aid>|<type>|<language>|<title>\n\
+# type: 1=primary title (one per anime), 2=synonyms (multiple per anime), 3=shorttitles \
+(multiple per anime), 4=official title (one per language)\n\
+1|1|x-jat|Seikai no Monshou\n\
+1|4|ja|\xe6\x98\x9f\xe7\x95\x8c\xe3\x81\xae\xe7\xb4\x8b\xe7\xab\xa0\n\
+1|2|en|Crest of the Stars\n\
+22|3|x-jat|Eva\n";
+
+    fn parse(input: &'static [u8]) -> Vec<Result<Title>> {
+        TitleIterator::from_reader(Cursor::new(input)).unwrap().collect()
+    }
+
+    fn title(id: u32, title_type: TitleType, language: &str, title: &str) -> Title {
+        Title {
+            id: id,
+            title_type: title_type,
+            language: language.to_string(),
+            title: title.to_string(),
+            raw_title: Some(title.to_string()),
+        }
+    }
+
+    #[test]
+    fn parses_titles_from_a_reader() {
+        let titles = parse(DUMP).into_iter().collect::<Result<Vec<_>>>().unwrap();
+
+        assert_eq!(titles,
+                   vec![title(1, TitleType::Primary, "x-jat", "Seikai no Monshou"),
+                        title(1, TitleType::Official, "ja", "星界の紋章"),
+                        title(1, TitleType::Synonym, "en", "Crest of the Stars"),
+                        title(22, TitleType::Short, "x-jat", "Eva")]);
+    }
+
+    fn gzip(input: &[u8]) -> Vec<u8> {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(input).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    // Returns one byte per read, like a slow pipe
+    struct Trickle<R>(R);
+
+    impl<R: Read> Read for Trickle<R> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let len = ::std::cmp::min(buf.len(), 1);
+            self.0.read(&mut buf[..len])
+        }
+    }
+
+    #[test]
+    fn parses_gzipped_titles() {
+        let expected = parse(DUMP).into_iter().collect::<Result<Vec<_>>>().unwrap();
+
+        let gzipped = TitleIterator::from_reader(Cursor::new(gzip(DUMP))).unwrap();
+        assert_eq!(gzipped.metadata().comments.len(), 3);
+        assert_eq!(gzipped.collect::<Result<Vec<_>>>().unwrap(), expected);
+
+        let trickled = TitleIterator::from_reader(Trickle(Cursor::new(gzip(DUMP)))).unwrap();
+        assert_eq!(trickled.collect::<Result<Vec<_>>>().unwrap(), expected);
+    }
+
+    #[test]
+    fn checks_magic_bytes_across_short_reads() {
+        let expected = parse(DUMP).into_iter().collect::<Result<Vec<_>>>().unwrap();
+
+        let plain = TitleIterator::from_reader(Trickle(Cursor::new(DUMP))).unwrap();
+        assert_eq!(plain.collect::<Result<Vec<_>>>().unwrap(), expected);
+
+        // Shorter than the magic bytes
+        let mut one_byte = String::new();
+        decompress(Trickle(Cursor::new(b"#"))).unwrap().read_to_string(&mut one_byte).unwrap();
+        assert_eq!(one_byte, "#");
+    }
+
+    #[test]
+    fn skips_header_comments() {
+        let titles = TitleIterator::from_reader(Cursor::new(DUMP)).unwrap();
+        let metadata = titles.metadata();
+
+        assert_eq!(metadata.comments.len(), 3);
+        assert_eq!(metadata.comments[1], "<aid>|<type>|<language>|<title>");
+
+        let created = metadata.created.expect("created date should be parsed");
+        assert_eq!((created.tm_year + 1900, created.tm_mon + 1, created.tm_mday),
+                   (2017, 3, 10));
+    }
+
+    #[test]
+    fn keeps_delimiters_in_titles() {
+        let input = b"#\n#\n#\n\
+9999|1|x-jat|Shin Evangelion Gekijouban:||\n\
+9999|2|en|A|B\n";
+        let titles = parse(input).into_iter().collect::<Result<Vec<_>>>().unwrap();
+
+        assert_eq!(titles[0].title, "Shin Evangelion Gekijouban:||");
+        assert_eq!(titles[1].title, "A|B");
+    }
+
+    #[test]
+    fn reports_line_numbers_of_bad_lines() {
+        let input = b"#\n#\n#\n\
+1|1|x-jat|Seikai no Monshou\n\
+abc|1|x-jat|Bad\n\
+2|1|x-jat|Seikai no Senki\n";
+        let results = parse(input);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap().id, 1);
+        assert_eq!(results[2].as_ref().unwrap().id, 2);
+
+        match *results[1].as_ref().unwrap_err().kind() {
+            ErrorKind::InvalidLine(line_num, ref raw) => {
+                assert_eq!(line_num, 4);
+                assert_eq!(raw, "abc|1|x-jat|Bad");
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+    }
+
+    #[test]
+    fn parses_without_trailing_newline() {
+        let titles = parse(b"#\n#\n#\n1|1|x-jat|Seikai no Monshou")
+            .into_iter()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(titles, vec![title(1, TitleType::Primary, "x-jat", "Seikai no Monshou")]);
+    }
+
+    #[test]
+    fn empty_dump_has_no_titles() {
+        assert!(parse(b"#\n#\n#\n").is_empty());
+    }
+
+    #[test]
+    fn parses_dat_type_ids() {
+        assert_eq!(TitleType::from_id("1"), TitleType::Primary);
+        assert_eq!(TitleType::from_id("2"), TitleType::Synonym);
+        assert_eq!(TitleType::from_id("3"), TitleType::Short);
+        assert_eq!(TitleType::from_id("4"), TitleType::Official);
+        assert_eq!(TitleType::from_id("5"), TitleType::Unknown("5".to_string()));
+    }
+
+    #[test]
+    fn parses_xml_type_names() {
+        assert_eq!(TitleType::from_xml_name("main"), TitleType::Primary);
+        assert_eq!(TitleType::from_xml_name("syn"), TitleType::Synonym);
+        assert_eq!(TitleType::from_xml_name("short"), TitleType::Short);
+        assert_eq!(TitleType::from_xml_name("official"), TitleType::Official);
+        assert_eq!(TitleType::from_xml_name("kana"), TitleType::Kana);
+        assert_eq!(TitleType::from_xml_name("card"), TitleType::Card);
+        assert_eq!(TitleType::from_xml_name("new"), TitleType::Unknown("new".to_string()));
+    }
+
+    #[test]
+    fn title_type_ids_and_names_round_trip() {
+        use TitleType::*;
+
+        for t in &[Primary, Synonym, Short, Official] {
+            assert_eq!(TitleType::from_id(t.id().unwrap()), *t);
+        }
+
+        for t in &[Primary, Synonym, Short, Official, Kana, Card] {
+            assert_eq!(TitleType::from_xml_name(t.name()), *t);
+        }
+
+        // Kana and card titles only exist in the XML dump
+        assert_eq!(Kana.id(), None);
+        assert_eq!(Card.id(), None);
+        assert_eq!(Unknown("7".to_string()).id(), Some("7"));
+        assert_eq!(Unknown("new".to_string()).id(), None);
+    }
+
+    #[test]
+    fn rejects_unknown_type_names() {
+        assert_eq!("kana".parse::<TitleType>().unwrap(), TitleType::Kana);
+        assert!("knaa".parse::<TitleType>().is_err());
+    }
+
+    #[test]
+    fn ranks_types_by_default_priority() {
+        use TitleType::*;
+        let priority = TitlePriority::default();
+
+        let ranks = [Primary, Official, Synonym, Short, Kana, Card, Unknown("9".to_string())]
+            .iter()
+            .map(|t| priority.rank(t))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ranks, vec![0, 1, 2, 3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn parses_title_priority() {
+        use TitleType::*;
+        let priority = "official, main,kana".parse::<TitlePriority>().unwrap();
+
+        assert_eq!(priority.0, vec![Official, Primary, Kana]);
+        assert_eq!(priority.rank(&Primary), 1);
+        assert_eq!(priority.rank(&Card), 3);
+        assert!("main,cards".parse::<TitlePriority>().is_err());
+    }
+}
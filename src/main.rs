@@ -3,21 +3,65 @@ extern crate serde_json;
 extern crate clubdarn;
 extern crate itertools;
 
-use indexer::Title;
+use indexer::{AnimeEntry, CheckedTitles, DumpFormat, DumpMetadata, ParseMode, ParseReport, Title,
+              TitleIterator, TitlePriority, TitleWriter, XmlTitleIterator};
+use indexer::conflicts::{ConflictPolicy, Conflicts};
 use indexer::elastic;
+use indexer::evaluate::{Evaluation, read_gold};
 use indexer::error::*;
+use indexer::matcher::{Candidate, Decision, MatchThresholds, Matcher, MemoryMatcher};
+use indexer::overrides::{Override, Overrides};
+use indexer::report::{MatchReport, MatchStatus, ReportCandidate, ReportEntry};
+use indexer::sequel::SequelMatcher;
 use itertools::Itertools;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::collections::hash_map::Entry;
 
 fn main() {
-    let mut args = std::env::args().skip(1);
-    let (path, url) = match (args.next(), args.next()) {
-        (Some(path), Some(url)) => (path, url),
-        _ => panic!("Invalid args"),
-    };
+    let mut args = std::env::args().skip(1).collect::<Vec<String>>();
+
+    let result = options(&mut args).and_then(|opts| {
+        match args.first().map(|s| s.as_str()) {
+            Some("parse") => {
+                match args.get(1) {
+                    Some(path) => parse(path, &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+            Some("merge") => {
+                match args.get(1) {
+                    Some(path) => merge(path, &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+            Some("evaluate") => {
+                match args.get(1) {
+                    Some(path) => evaluate(path, args.get(2), &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+            Some("review") => {
+                match args.get(1) {
+                    Some(path) => review(path, args.get(2), &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+            Some("diff") => {
+                match (args.get(1), args.get(2)) {
+                    (Some(old_path), Some(new_path)) => diff(old_path, new_path, &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+            _ => {
+                match (args.get(0), args.get(1)) {
+                    (Some(path), Some(url)) => run(path, url, &opts),
+                    _ => panic!("Invalid args"),
+                }
+            }
+        }
+    });
 
-    if let Err(e) = run(&path, &url) {
+    if let Err(e) = result {
         use std::io::Write;
 
         let stderr = &mut std::io::stderr();
@@ -38,120 +82,969 @@ fn main() {
     }
 }
 
-fn run(path: &str, url: &str) -> Result<()> {
-    let alias = "series";
-    let search_client = elastic::Client::new(url, alias, "series")?;
+struct Options {
+    format: DumpFormat,
+    mode: ParseMode,
+    priority: TitlePriority,
+    unsorted: bool,
+    json: bool,
+    dat: bool,
+    thresholds: MatchThresholds,
+    overrides: Option<String>,
+    report_json: Option<String>,
+    report_csv: Option<String>,
+    conflict_policy: ConflictPolicy,
+    gold: Option<String>,
+    previous: Option<String>,
+    save: Option<String>,
+    bulk_concurrency: usize,
+    retry: elastic::RetryPolicy,
+    index_config: Option<String>,
+}
+
+fn options(args: &mut Vec<String>) -> Result<Options> {
+    let format = match take_option(args, "--format") {
+        Some(f) => f.parse::<DumpFormat>()?,
+        None => DumpFormat::Dat,
+    };
+
+    let max_bad_ratio = match take_option(args, "--max-bad-ratio") {
+        Some(r) => r.parse::<f64>().chain_err(|| format!("invalid --max-bad-ratio {}", r))?,
+        None => 0.01,
+    };
+
+    let mode = if take_flag(args, "--lenient") {
+        ParseMode::Lenient { max_bad_ratio: max_bad_ratio }
+    } else {
+        ParseMode::Strict
+    };
+
+    let priority = match take_option(args, "--title-priority") {
+        Some(p) => p.parse::<TitlePriority>()?,
+        None => TitlePriority::default(),
+    };
+
+    let mut thresholds = MatchThresholds::default();
+
+    if let Some(n) = take_option(args, "--candidates") {
+        thresholds.candidates = n.parse::<usize>()
+            .chain_err(|| format!("invalid --candidates {}", n))?;
+    }
+
+    if let Some(score) = take_option(args, "--min-score") {
+        thresholds.min_score = score.parse::<f64>()
+            .chain_err(|| format!("invalid --min-score {}", score))?;
+    }
+
+    if let Some(margin) = take_option(args, "--min-margin") {
+        thresholds.min_margin = margin.parse::<f64>()
+            .chain_err(|| format!("invalid --min-margin {}", margin))?;
+    }
+
+    thresholds.validate()?;
+
+    let bulk_concurrency = match take_option(args, "--bulk-concurrency") {
+        Some(n) => n.parse::<usize>().chain_err(|| format!("invalid --bulk-concurrency {}", n))?,
+        None => 4,
+    };
+
+    let mut retry = elastic::RetryPolicy::default();
+
+    if let Some(n) = take_option(args, "--max-attempts") {
+        retry.max_attempts = n.parse::<u32>()
+            .chain_err(|| format!("invalid --max-attempts {}", n))?;
+    }
+
+    let conflict_policy = match take_option(args, "--conflicts") {
+        Some(p) => p.parse::<ConflictPolicy>()?,
+        None => ConflictPolicy::default(),
+    };
+
+    Ok(Options {
+        format: format,
+        mode: mode,
+        priority: priority,
+        unsorted: take_flag(args, "--unsorted"),
+        json: take_flag(args, "--json"),
+        dat: take_flag(args, "--dat"),
+        thresholds: thresholds,
+        overrides: take_option(args, "--overrides"),
+        report_json: take_option(args, "--report-json"),
+        report_csv: take_option(args, "--report-csv"),
+        conflict_policy: conflict_policy,
+        gold: take_option(args, "--gold"),
+        previous: take_option(args, "--previous"),
+        save: take_option(args, "--save"),
+        bulk_concurrency: bulk_concurrency,
+        retry: retry,
+        index_config: take_option(args, "--index-config"),
+    })
+}
+
+// Removes `--name` from the args, returning whether it was present
+fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|a| a == name) {
+        Some(pos) => {
+            args.remove(pos);
+            true
+        }
+        None => false,
+    }
+}
+
+// Removes `--name value` from the args, returning the value
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let pos = match args.iter().position(|a| a == name) {
+        Some(pos) => pos,
+        None => return None,
+    };
+    args.remove(pos);
+
+    if pos < args.len() {
+        Some(args.remove(pos))
+    } else {
+        panic!("Missing value for {}", name)
+    }
+}
+
+type Titles = Box<Iterator<Item = Result<Title>>>;
+
+// A path of "-" reads the dump from stdin. Only the `.dat` dump has header
+// metadata, so it's empty for XML.
+fn open_titles(path: &str, opts: &Options) -> Result<(CheckedTitles<Titles>, DumpMetadata)> {
+    let (titles, metadata): (Titles, DumpMetadata) = match opts.format {
+        DumpFormat::Dat => {
+            let iter = if path == "-" {
+                TitleIterator::from_reader(std::io::stdin())?
+            } else {
+                TitleIterator::new(path)?
+            };
+
+            let metadata = iter.metadata().clone();
+            (Box::new(iter), metadata)
+        }
+        DumpFormat::Xml => {
+            let iter = if path == "-" {
+                XmlTitleIterator::from_reader(std::io::stdin())?
+            } else {
+                XmlTitleIterator::new(path)?
+            };
+
+            (Box::new(iter), DumpMetadata::default())
+        }
+    };
+
+    Ok((CheckedTitles::new(titles, opts.mode), metadata))
+}
+
+fn print_parse_report(report: &ParseReport) {
+    if report.bad_lines.is_empty() {
+        return;
+    }
+
+    println!("Skipped {} of {} lines that failed to parse:",
+             report.bad_lines.len(),
+             report.total());
+
+    for bad_line in &report.bad_lines {
+        println!("  line {}: {} ({})",
+                 bad_line.line_number,
+                 bad_line.raw,
+                 bad_line.reason);
+    }
+}
+
+// Prints one parsed title per line, tab-separated, or in AniDB's `.dat`
+// format with `--dat`
+fn parse(path: &str, opts: &Options) -> Result<()> {
+    use std::io::Write;
+
+    let (mut titles, metadata) = open_titles(path, opts)?;
+    let result = write_titles(&mut titles, &metadata, opts);
+
+    // Titles go to stdout, so the report goes to stderr. It's written even if
+    // parsing failed, to show which lines were bad.
+    let stderr = &mut std::io::stderr();
+    for bad_line in &titles.report().bad_lines {
+        writeln!(stderr,
+                 "skipped line {}: {} ({})",
+                 bad_line.line_number,
+                 bad_line.raw,
+                 bad_line.reason)?;
+    }
+
+    result
+}
+
+fn write_titles(titles: &mut CheckedTitles<Titles>,
+                metadata: &DumpMetadata,
+                opts: &Options)
+                -> Result<()> {
+    use std::io::Write;
 
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    if opts.dat {
+        let mut writer = TitleWriter::new(out, metadata)?;
+        for title_result in titles {
+            writer.write(&title_result?)?;
+        }
+    } else {
+        for title_result in titles {
+            let title = title_result?;
+            writeln!(out,
+                     "{}\t{:?}\t{}\t{}",
+                     title.id,
+                     title.title_type,
+                     title.language,
+                     title.title)?;
+        }
+    }
+
+    Ok(())
+}
+
+// Prints the differences between two dumps, as JSON or a table
+fn diff(old_path: &str, new_path: &str, opts: &Options) -> Result<()> {
+    use std::io::Write;
+
+    let (old_titles, _) = open_titles(old_path, opts)?;
+    let (new_titles, _) = open_titles(new_path, opts)?;
+
+    let diffs = indexer::diff::diff(old_titles, new_titles)?;
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    if opts.json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&diffs)?)?;
+        return Ok(());
+    }
+
+    writeln!(out,
+             "{:<8} {:<8} {:<8} {:<9} {}",
+             "ID",
+             "CHANGE",
+             "LANG",
+             "TYPE",
+             "TITLE")?;
+
+    for anime in &diffs {
+        for title in &anime.titles {
+            let text = match (&title.old, &title.new) {
+                (&Some(ref old), &Some(ref new)) => format!("{} -> {}", old, new),
+                (&Some(ref t), &None) |
+                (&None, &Some(ref t)) => t.clone(),
+                (&None, &None) => String::new(),
+            };
+
+            writeln!(out,
+                     "{:<8} {:<8} {:<8} {:<9} {}",
+                     anime.id,
+                     title.kind.name(),
+                     title.language,
+                     title.title_type.name(),
+                     text)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn get_clubdam_titles() -> Result<Vec<String>> {
     let darn = clubdarn::Client::default()?;
 
-    println!("Getting series from ClubDAM");
     let series = darn.series()
         .by_category(clubdarn::category::series::ANIME)
         .send()
         .chain_err(|| "failed to get series from ClubDAM (maybe it's down?)")?;
 
-    println!("Reindexing AniDB titles to Elasticsearch");
-    let old_indices = reindex(&search_client, path)?;
+    Ok(series.items.into_iter().map(|s| s.title).collect())
+}
 
-    let languages = ["ja"];
+#[derive(Default)]
+struct Matches {
+    anidb_id_to_clubdam_titles: HashMap<String, Vec<String>>,
+    clubdam_titles_not_in_anidb: Vec<elastic::Series>,
+    report: MatchReport,
+}
 
-    println!("Searching Elasticsearch for ClubDAM series names ({})",
-             series.items.len());
+impl Matches {
+    // Series exists in ClubDAM and AniDB, we should update the indexed docs
+    // to include the ClubDAM title
+    fn add_match(&mut self, anidb_id: String, clubdam_title: String) {
+        match self.anidb_id_to_clubdam_titles.entry(anidb_id) {
+            Entry::Occupied(mut o) => {
+                o.get_mut().push(clubdam_title);
+            }
+            Entry::Vacant(v) => {
+                v.insert(vec![clubdam_title]);
+            }
+        };
+    }
+
+    // Series exists in ClubDAM but not AniDB, we should insert the ClubDAM
+    // titles into Elasticsearch
+    fn add_unmatched(&mut self, clubdam_title: String) {
+        let mut titles_map = HashMap::with_capacity(1);
+        let mut titles_vec: Vec<String> = Vec::with_capacity(1);
+        titles_vec.push(clubdam_title.clone());
+        titles_map.insert("clubdam".to_string(), titles_vec);
+
+        let titles_by_language = elastic::TitlesByLanguage(titles_map);
+        let normalized_titles = titles_by_language.normalized();
+
+        let series = elastic::Series {
+            id: clubdam_title.clone(),
+            main_title: Some(clubdam_title),
+            titles: titles_by_language,
+            normalized_titles: normalized_titles,
+        };
 
-    let batch_size = 500;
+        self.report.clubdam_titles_not_in_anidb.push(clubdam_title.clone());
+        self.clubdam_titles_not_in_anidb.push(series);
+    }
 
-    let mut anidb_id_to_clubdam_titles: HashMap<String, Vec<String>> = HashMap::new();
-    let mut clubdam_titles_not_in_anidb: Vec<elastic::Series> = Vec::new();
+    fn add_report_entry(&mut self,
+                        clubdam_title: String,
+                        status: MatchStatus,
+                        chosen: Option<ReportCandidate>,
+                        alternatives: &[Candidate]) {
+        self.report.entries.push(ReportEntry {
+            clubdam_title: clubdam_title,
+            status: status,
+            chosen: chosen,
+            alternatives: alternatives.iter().map(ReportCandidate::from).collect(),
+        });
+    }
+}
 
-    for chunk in &series.items.into_iter().chunks(batch_size) {
-        let series_batch = chunk.collect::<Vec<clubdarn::Series>>();
-        let titles = series_batch.iter().map(|s| s.title.clone()).collect::<Vec<String>>();
+fn match_clubdam_series<M>(matcher: &M,
+                           clubdam_titles: Vec<String>,
+                           opts: &Options,
+                           overrides: &Overrides,
+                           batch_size: usize)
+                           -> Result<Matches>
+    where M: Matcher
+{
+    let thresholds = &opts.thresholds;
+    let mut matches = Matches::default();
+    let mut accepted = Vec::new();
+    let mut forced_matches = Vec::new();
+    let mut forced_unmatched = Vec::new();
+    let mut excluded = Vec::new();
 
-        let search_results = search_client.multi_search(&titles, &languages)?;
+    // Overrides are applied in two steps. Excluded titles are dropped before
+    // searching, since they're left out entirely.
+    let to_search = clubdam_titles.into_iter().filter(|title| {
+        if overrides.get(title) == Some(Override::Exclude) {
+            excluded.push(title.clone());
+            false
+        } else {
+            true
+        }
+    });
 
-        let zipped = series_batch.into_iter().zip(search_results);
+    for chunk in &to_search.chunks(batch_size) {
+        let titles = chunk.collect::<Vec<String>>();
+        let search_results = matcher.find_candidates(&titles, thresholds.candidates)?;
 
-        for (clubdarn_series, anidb_series_opt) in zipped {
-            if let Some(anidb_series) = anidb_series_opt {
-                // Series exists in ClubDAM and AniDB, we should update the
-                // indexed docs to include the ClubDAM title
-                match anidb_id_to_clubdam_titles.entry(anidb_series.id.to_string()) {
-                    Entry::Occupied(mut o) => {
-                        o.get_mut().push(clubdarn_series.title);
-                    }
-                    Entry::Vacant(v) => {
-                        v.insert(vec![clubdarn_series.title]);
-                    }
-                };
-            } else {
-                // Series exists in ClubDAM but not AniDB, we should insert
-                // the ClubDAM titles into Elasticsearch
-                let mut titles_map = HashMap::with_capacity(1);
-                let mut titles_vec: Vec<String> = Vec::with_capacity(1);
-                titles_vec.push(clubdarn_series.title.clone());
-                titles_map.insert("clubdam".to_string(), titles_vec);
-
-                let series = elastic::Series {
-                    id: clubdarn_series.title.clone(),
-                    main_title: Some(clubdarn_series.title),
-                    titles: elastic::TitlesByLanguage(titles_map),
-                };
-
-                clubdam_titles_not_in_anidb.push(series);
+        for (title, candidates) in titles.into_iter().zip(search_results) {
+            // Other overrides replace the search result afterwards, so that
+            // the report still shows what was found (e.g. to tell when an
+            // override is no longer needed)
+            match overrides.get(&title) {
+                Some(Override::Match(id)) => {
+                    forced_matches.push((id, title, candidates));
+                    continue;
+                }
+                Some(Override::NoMatch) => {
+                    forced_unmatched.push((title, candidates));
+                    continue;
+                }
+                _ => {}
             }
+
+            // Weak or ambiguous matches are treated as not being in AniDB,
+            // rather than risking attaching the title to the wrong series
+            let status = match thresholds.decide(&candidates) {
+                Decision::Accepted => {
+                    accepted.push((title, candidates));
+                    continue;
+                }
+                Decision::NoCandidates => MatchStatus::NoCandidates,
+                Decision::BelowMinScore => MatchStatus::BelowMinScore,
+                Decision::Ambiguous => MatchStatus::Ambiguous,
+            };
+
+            matches.add_unmatched(title.clone());
+            matches.add_report_entry(title, status, None, &candidates);
         }
     }
 
+    // Accepted matches are only applied once all titles have been searched,
+    // since conflicts can't be detected before then
+    let overridden = forced_matches.iter()
+        .map(|&(id, ref title, _)| (title.clone(), id.to_string()))
+        .collect::<Vec<_>>();
+    let conflicts = Conflicts::find(&accepted, &overridden);
+    let rejected = opts.conflict_policy.rejected(&conflicts);
+
+    for (title, candidates) in accepted {
+        if rejected.contains(&title) {
+            matches.add_unmatched(title.clone());
+            matches.add_report_entry(title, MatchStatus::Conflict, None, &candidates);
+        } else {
+            matches.add_match(candidates[0].series.id.clone(), title.clone());
+            matches.add_report_entry(title,
+                                     MatchStatus::Matched,
+                                     Some(ReportCandidate::from(&candidates[0])),
+                                     &candidates[1..]);
+        }
+    }
+
+    matches.report.conflicts = conflicts;
+
+    for (id, title, candidates) in forced_matches {
+        let id = id.to_string();
+        matches.add_match(id.clone(), title.clone());
+
+        // The forced series may not have been found by the search at all, in
+        // which case only its ID is known
+        let (found, alternatives): (Vec<Candidate>, Vec<Candidate>) =
+            candidates.into_iter().partition(|c| c.series.id == id);
+
+        let chosen = match found.first() {
+            Some(candidate) => ReportCandidate::from(candidate),
+            None => {
+                ReportCandidate {
+                    anidb_id: id,
+                    main_title: None,
+                    score: 0.0,
+                    matched_fields: Vec::new(),
+                }
+            }
+        };
+
+        matches.add_report_entry(title, MatchStatus::OverrideMatch, Some(chosen), &alternatives);
+    }
+
+    for (title, candidates) in forced_unmatched {
+        matches.add_unmatched(title.clone());
+        matches.add_report_entry(title, MatchStatus::OverrideNoMatch, None, &candidates);
+    }
+
+    for title in excluded {
+        matches.add_report_entry(title, MatchStatus::Excluded, None, &[]);
+    }
+
+    Ok(matches)
+}
+
+fn print_conflicts<W>(out: &mut W, conflicts: &Conflicts) -> Result<()>
+    where W: std::io::Write
+{
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out,
+             "Found {} AniDB series matched by multiple ClubDAM titles, and {} tied matches",
+             conflicts.many_to_one.len(),
+             conflicts.ties.len())?;
+
+    for m in &conflicts.many_to_one {
+        let titles = m.clubdam_titles
+            .iter()
+            .map(|t| if t.overridden {
+                format!("\"{}\" (override)", t.clubdam_title)
+            } else {
+                format!("\"{}\" ({:.2})", t.clubdam_title, t.score)
+            })
+            .collect::<Vec<_>>();
+        writeln!(out, "  AniDB {}: {}", m.anidb_id, titles.join(", "))?;
+    }
+
+    for tie in &conflicts.ties {
+        writeln!(out,
+                 "  \"{}\" ({:.2}): tied between AniDB {}",
+                 tie.clubdam_title,
+                 tie.score,
+                 tie.anidb_ids.join(", "))?;
+    }
+
+    Ok(())
+}
+
+fn write_report(report: &MatchReport, opts: &Options) -> Result<()> {
+    if let Some(ref path) = opts.report_json {
+        report.write_json(path).chain_err(|| format!("failed to write report to {}", path))?;
+    }
+
+    if let Some(ref path) = opts.report_csv {
+        report.write_csv(path).chain_err(|| format!("failed to write report to {}", path))?;
+    }
+
+    Ok(())
+}
+
+fn load_overrides(opts: &Options) -> Result<Overrides> {
+    match opts.overrides {
+        Some(ref path) => Overrides::from_file(path),
+        None => Ok(Overrides::default()),
+    }
+}
+
+const LANGUAGES: [&'static str; 1] = ["ja"];
+const BATCH_SIZE: usize = 500;
+
+fn run(path: &str, url: &str, opts: &Options) -> Result<()> {
+    let alias = "series";
+    let search_client = connect(url, alias, opts)?;
+    println!("Connected to {}", search_client.version());
+
+    let overrides = load_overrides(opts)?;
+
+    println!("Getting series from ClubDAM");
+    let titles = get_clubdam_titles()?;
+
+    println!("Reindexing AniDB titles to Elasticsearch");
+    let old_indices = reindex(&search_client, path, opts, &overrides)?;
+
+    println!("Searching Elasticsearch for ClubDAM series names ({})",
+             titles.len());
+
+    let matcher = SequelMatcher::new(elastic::ElasticMatcher::new(&search_client, &LANGUAGES));
+    let Matches { mut anidb_id_to_clubdam_titles, clubdam_titles_not_in_anidb, report } =
+        match_clubdam_series(&matcher, titles, opts, &overrides, BATCH_SIZE)?;
+
+    write_report(&report, opts)?;
+    print_conflicts(&mut std::io::stdout(), &report.conflicts)?;
+
     println!("Updating existing Elasticsearch documents to include ClubDAM titles ({})",
              anidb_id_to_clubdam_titles.len());
-    for chunk in &anidb_id_to_clubdam_titles.drain().chunks(batch_size) {
+    for chunk in &anidb_id_to_clubdam_titles.drain().chunks(BATCH_SIZE) {
         search_client.bulk_update(chunk, true)?;
     }
 
     println!("Updating Elasticsearch with unmatched ClubDAM titles ({})",
              clubdam_titles_not_in_anidb.len());
-    for chunk in &clubdam_titles_not_in_anidb.into_iter().chunks(batch_size) {
+    for chunk in &clubdam_titles_not_in_anidb.into_iter().chunks(BATCH_SIZE) {
         search_client.bulk_insert(alias, chunk, true)?;
     }
 
     println!("Deleting non-ClubDAM documents");
 
-    search_client.delete_non_clubdam(batch_size)?;
+    search_client.delete_non_clubdam(BATCH_SIZE)?;
 
     println!("Deleting old Elasticsearch indices {:?}", old_indices);
 
     search_client.delete_indices(&old_indices)
 }
 
-fn reindex(client: &elastic::Client, path: &str) -> Result<Vec<String>> {
-    let titles_iter = indexer::TitleIterator::new(path)?;
+// Loads (and validates) the index config before connecting, so that a bad
+// config file fails fast. Checks that depend on the server's version are done
+// once connected.
+fn connect<'a>(url: &'a str, alias: &'a str, opts: &Options) -> Result<elastic::Client<'a>> {
+    let index_config = match opts.index_config {
+        Some(ref path) => Some(elastic::IndexConfig::from_file(path)?),
+        None => None,
+    };
 
-    let mut titles_hash_map: HashMap<u32, Vec<Title>> = HashMap::new();
+    let client = elastic::Client::new(url, alias, "series", opts.retry.clone())?;
 
-    for title_result in titles_iter {
-        let title = title_result?;
+    Ok(match index_config {
+        Some(index_config) => client.with_index_config(index_config)?,
+        None => client,
+    })
+}
 
-        match titles_hash_map.entry(title.id) {
-            Entry::Occupied(mut o) => {
-                o.get_mut().push(title);
-            }
-            Entry::Vacant(v) => {
-                v.insert(vec![title]);
+// Like `run`, but matches in memory instead of using Elasticsearch. Prints the
+// resulting documents to stdout as JSON, one per line.
+fn merge(path: &str, opts: &Options) -> Result<()> {
+    use std::io::Write;
+
+    let stderr = &mut std::io::stderr();
+
+    let overrides = load_overrides(opts)?;
+
+    writeln!(stderr, "Getting series from ClubDAM")?;
+    let titles = get_clubdam_titles()?;
+
+    writeln!(stderr, "Loading AniDB titles")?;
+    let anidb_series = load_series(path, opts)?;
+
+    let anidb_ids = anidb_series.iter().filter_map(|s| s.id.parse::<u32>().ok()).collect();
+    overrides.validate(&anidb_ids)?;
+
+    writeln!(stderr,
+             "Matching ClubDAM series names ({}) against AniDB series ({})",
+             titles.len(),
+             anidb_series.len())?;
+
+    let matcher = SequelMatcher::new(MemoryMatcher::new(anidb_series, &LANGUAGES));
+    let Matches { mut anidb_id_to_clubdam_titles, clubdam_titles_not_in_anidb, report } =
+        match_clubdam_series(&matcher, titles, opts, &overrides, BATCH_SIZE)?;
+
+    write_report(&report, opts)?;
+    print_conflicts(stderr, &report.conflicts)?;
+
+    writeln!(stderr,
+             "Matched {} AniDB series, {} ClubDAM series unmatched",
+             anidb_id_to_clubdam_titles.len(),
+             clubdam_titles_not_in_anidb.len())?;
+
+    // Only keep AniDB series that have a ClubDAM title
+    let matched = matcher.into_inner()
+        .into_series()
+        .into_iter()
+        .filter_map(|mut series| {
+            anidb_id_to_clubdam_titles.remove(&series.id).map(|titles| {
+                series.titles.0.insert("clubdam".to_string(), titles);
+                series
+            })
+        });
+
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+
+    for series in matched.chain(clubdam_titles_not_in_anidb) {
+        writeln!(out, "{}", serde_json::to_string(&series)?)?;
+    }
+
+    Ok(())
+}
+
+// Measures match quality against a gold standard file of ClubDAM titles and
+// the AniDB IDs they should be matched to. Matches with Elasticsearch if a URL
+// is given (using a separate alias, so the live index isn't touched), or in
+// memory otherwise.
+fn evaluate(path: &str, url: Option<&String>, opts: &Options) -> Result<()> {
+    let gold_path = match opts.gold {
+        Some(ref path) => path,
+        None => return Err("evaluate requires --gold <file>".into()),
+    };
+
+    let gold = read_gold(gold_path)?;
+    let overrides = load_overrides(opts)?;
+    let titles = gold.iter().map(|pair| pair.clubdam_title.clone()).collect::<Vec<_>>();
+
+    let matches = match url {
+        Some(url) => {
+            let client = connect(url, "series_evaluation", opts)?;
+
+            println!("Reindexing AniDB titles to Elasticsearch");
+            let old_indices = reindex(&client, path, opts, &overrides)?;
+            client.delete_indices(&old_indices)?;
+
+            let matcher = SequelMatcher::new(elastic::ElasticMatcher::new(&client, &LANGUAGES));
+            match_clubdam_series(&matcher, titles, opts, &overrides, BATCH_SIZE)?
+        }
+        None => {
+            println!("Loading AniDB titles");
+            let anidb_series = load_series(path, opts)?;
+
+            let anidb_ids = anidb_series.iter().filter_map(|s| s.id.parse::<u32>().ok()).collect();
+            overrides.validate(&anidb_ids)?;
+
+            let matcher = SequelMatcher::new(MemoryMatcher::new(anidb_series, &LANGUAGES));
+            match_clubdam_series(&matcher, titles, opts, &overrides, BATCH_SIZE)?
+        }
+    };
+
+    let mut predicted = HashMap::new();
+    for (id, titles) in matches.anidb_id_to_clubdam_titles {
+        if let Ok(id) = id.parse::<u32>() {
+            for title in titles {
+                predicted.insert(title, id);
             }
-        };
+        }
     }
 
-    let series = titles_hash_map.drain().map(|(id, titles)| {
-        let titles_by_language = elastic::TitlesByLanguage::new(titles);
-        let main_title = titles_by_language.main_title("ja");
-        elastic::Series {
-            id: id.to_string(),
-            main_title: main_title,
-            titles: titles_by_language,
+    let evaluation = Evaluation::new(&gold, &predicted);
+    let correct = evaluation.predictions.iter().filter(|p| p.is_correct()).count();
+
+    println!("Precision: {:.3}", evaluation.precision);
+    println!("Recall: {:.3}", evaluation.recall);
+    println!("Correct: {} of {}", correct, evaluation.predictions.len());
+
+    if let Some(ref previous_path) = opts.previous {
+        let previous = Evaluation::from_file(previous_path)?;
+
+        println!("Previous precision: {:.3}, recall: {:.3}",
+                 previous.precision,
+                 previous.recall);
+
+        let regressions = evaluation.regressions(&previous);
+        println!("Regressions since previous run ({}):", regressions.len());
+
+        for p in regressions {
+            println!("  \"{}\": expected {}, got {}",
+                     p.clubdam_title,
+                     format_id(p.expected),
+                     format_id(p.predicted));
+        }
+    }
+
+    if let Some(ref save_path) = opts.save {
+        evaluation.write_json(save_path)?;
+    }
+
+    Ok(())
+}
+
+fn format_id(id: Option<u32>) -> String {
+    match id {
+        Some(id) => id.to_string(),
+        None => "no match".to_string(),
+    }
+}
+
+// Walks through the ClubDAM series that wouldn't be matched automatically,
+// asking which candidate (if any) is correct. Decisions are appended to the
+// overrides file as they're made, so the review can be stopped at any time and
+// picks up where it left off. As with `evaluate`, matches with Elasticsearch
+// (using a separate alias) if a URL is given, so that scores are on the same
+// scale as in `run`, or in memory otherwise.
+fn review(path: &str, url: Option<&String>, opts: &Options) -> Result<()> {
+    let overrides_path = match opts.overrides {
+        Some(ref path) => path,
+        None => return Err("review requires --overrides <file> to save decisions to".into()),
+    };
+
+    let mut overrides = if std::path::Path::new(overrides_path).exists() {
+        Overrides::from_file(overrides_path)?
+    } else {
+        Overrides::default()
+    };
+
+    println!("Getting series from ClubDAM");
+    let titles = get_clubdam_titles()?;
+
+    // Titles matched by overrides aren't reviewed again, but count towards
+    // conflicts as they do in `run`
+    let overridden = titles.iter()
+        .filter_map(|title| match overrides.get(title) {
+            Some(Override::Match(id)) => Some((title.clone(), id.to_string())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    // Each title only needs one decision, even if ClubDAM lists it twice
+    let mut seen = HashSet::new();
+    let titles = titles.into_iter()
+        .filter(|title| overrides.get(title).is_none() && seen.insert(title.trim().to_string()))
+        .collect::<Vec<String>>();
+
+    match url {
+        Some(url) => {
+            let client = connect(url, "series_review", opts)?;
+
+            println!("Reindexing AniDB titles to Elasticsearch");
+            let old_indices = reindex(&client, path, opts, &overrides)?;
+            client.delete_indices(&old_indices)?;
+
+            let matcher = SequelMatcher::new(elastic::ElasticMatcher::new(&client, &LANGUAGES));
+            review_titles(&matcher, titles, &overridden, opts, &mut overrides, overrides_path)
+        }
+        None => {
+            println!("Loading AniDB titles");
+            let anidb_series = load_series(path, opts)?;
+
+            let anidb_ids = anidb_series.iter().filter_map(|s| s.id.parse::<u32>().ok()).collect();
+            overrides.validate(&anidb_ids)?;
+
+            let matcher = SequelMatcher::new(MemoryMatcher::new(anidb_series, &LANGUAGES));
+            review_titles(&matcher, titles, &overridden, opts, &mut overrides, overrides_path)
+        }
+    }
+}
+
+fn review_titles<M>(matcher: &M,
+                    titles: Vec<String>,
+                    overridden: &[(String, String)],
+                    opts: &Options,
+                    overrides: &mut Overrides,
+                    overrides_path: &str)
+                    -> Result<()>
+    where M: Matcher
+{
+    let results = matcher.find_candidates(&titles, opts.thresholds.candidates)?;
+
+    let (accepted, mut to_review): (Vec<_>, Vec<_>) = titles.into_iter()
+        .zip(results)
+        .filter(|&(_, ref candidates)| !candidates.is_empty())
+        .partition(|&(_, ref candidates)| opts.thresholds.decide(candidates) == Decision::Accepted);
+
+    // Accepted matches rejected by the conflict policy need reviewing too
+    let rejected = opts.conflict_policy.rejected(&Conflicts::find(&accepted, overridden));
+    to_review.extend(accepted.into_iter().filter(|&(ref title, _)| rejected.contains(title)));
+
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+
+    for (i, &(ref title, ref candidates)) in to_review.iter().enumerate() {
+        println!("");
+        println!("[{}/{}] {}", i + 1, to_review.len(), title);
+
+        for (n, candidate) in candidates.iter().enumerate() {
+            print_candidate(n + 1, candidate);
+        }
+
+        match prompt(&mut input, candidates)? {
+            Choice::Decide(decision) => overrides.append(overrides_path, title, decision)?,
+            Choice::Skip => {}
+            Choice::Quit => break,
+        }
+    }
+
+    Ok(())
+}
+
+enum Choice {
+    Decide(Override),
+    Skip,
+    Quit,
+}
+
+// Asks for a decision until a valid one is given. End of input counts as quit.
+fn prompt<R>(input: &mut R, candidates: &[Candidate]) -> Result<Choice>
+    where R: std::io::BufRead
+{
+    use std::io::Write;
+
+    loop {
+        print!("Match 1-{}, (n)o match, e(x)clude, (s)kip or (q)uit? ",
+               candidates.len());
+        std::io::stdout().flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(Choice::Quit);
+        }
+
+        match line.trim() {
+            "n" => return Ok(Choice::Decide(Override::NoMatch)),
+            "x" => return Ok(Choice::Decide(Override::Exclude)),
+            "s" => return Ok(Choice::Skip),
+            "q" => return Ok(Choice::Quit),
+            other => {
+                let candidate = other.parse::<usize>()
+                    .ok()
+                    .and_then(|n| if n > 0 { candidates.get(n - 1) } else { None });
+
+                match candidate.and_then(|c| c.series.id.parse::<u32>().ok()) {
+                    Some(id) => return Ok(Choice::Decide(Override::Match(id))),
+                    None => println!("Invalid choice \"{}\"", other),
+                }
+            }
         }
+    }
+}
+
+fn print_candidate(n: usize, candidate: &Candidate) {
+    let series = &candidate.series;
+
+    println!("  {}) AniDB {} \"{}\" (score {:.2}, matched {})",
+             n,
+             series.id,
+             series.main_title.as_ref().map_or("", |t| t.as_str()),
+             candidate.score,
+             candidate.matched_fields.join(", "));
+
+    let mut languages = series.titles.0.keys().collect::<Vec<_>>();
+    languages.sort();
+
+    for language in languages {
+        println!("       {}: {}", language, series.titles.0[language].join(" / "));
+    }
+}
+
+// Reads all series from the dump into memory
+fn load_series(path: &str, opts: &Options) -> Result<Vec<elastic::Series>> {
+    let (mut titles_iter, _) = open_titles(path, opts)?;
+
+    let entries = if opts.unsorted {
+        indexer::group_by_anime_unsorted(titles_iter.by_ref())
+    } else {
+        indexer::group_by_anime(titles_iter.by_ref()).collect::<Result<Vec<_>>>()
+    };
+
+    let report = titles_iter.report();
+    if !report.bad_lines.is_empty() {
+        use std::io::Write;
+        writeln!(&mut std::io::stderr(),
+                 "Skipped {} of {} lines that failed to parse",
+                 report.bad_lines.len(),
+                 report.total())?;
+    }
+
+    let series = entries?
+        .into_iter()
+        .map(|entry| elastic::Series::from_anime(entry, &opts.priority))
+        .collect();
+
+    Ok(series)
+}
+
+// Indexes the dump into a new index, checks the overrides against it, and
+// only then points the alias at it. Returns the old indices for the alias.
+fn reindex(client: &elastic::Client,
+           path: &str,
+           opts: &Options,
+           overrides: &Overrides)
+           -> Result<Vec<String>> {
+    let (mut titles_iter, metadata) = open_titles(path, opts)?;
+
+    if let Some(created) = metadata.created {
+        println!("AniDB dump was created at {}", created.rfc3339());
+    }
+
+    let mut anidb_ids = HashSet::new();
+
+    let result = if opts.unsorted {
+        indexer::group_by_anime_unsorted(titles_iter.by_ref()).and_then(|entries| {
+            index_entries(client, entries.into_iter().map(Ok), &metadata, opts, &mut anidb_ids)
+        })
+    } else {
+        let entries = indexer::group_by_anime(titles_iter.by_ref());
+        index_entries(client, entries, &metadata, opts, &mut anidb_ids)
+    };
+
+    // Printed before checking the result, so that the bad lines are still
+    // shown when there were too many of them
+    print_parse_report(titles_iter.report());
+
+    let new_index = result?;
+
+    // Overrides can only be checked once all IDs are known, but must be
+    // checked before the alias is moved, so that a bad overrides file doesn't
+    // leave searches on an index that the rest of the run never finished
+    if let Err(e) = overrides.validate(&anidb_ids) {
+        client.discard_index(&new_index);
+        return Err(e);
+    }
+
+    client.swap_alias(&new_index)
+}
+
+// Returns the name of the new index
+fn index_entries<I>(client: &elastic::Client,
+                    entries: I,
+                    metadata: &DumpMetadata,
+                    opts: &Options,
+                    anidb_ids: &mut HashSet<u32>)
+                    -> Result<String>
+    where I: Iterator<Item = Result<AnimeEntry>>
+{
+    let series = entries.map(|entry_result| {
+        entry_result.map(|entry| {
+            anidb_ids.insert(entry.id);
+            elastic::Series::from_anime(entry, &opts.priority)
+        })
     });
 
     let chunk_size = 1000;
-    let should_wait = true;
-    client.reindex(series, chunk_size, should_wait)
+    client.build_index(series, metadata, chunk_size, opts.bulk_concurrency)
 }
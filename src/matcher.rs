@@ -0,0 +1,499 @@
+use elastic::Series;
+use error::*;
+use normalize::normalize;
+use romaji;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+
+/// Finds the AniDB series that best match a ClubDAM title.
+pub trait Matcher {
+    /// Returns up to `limit` candidates for each title, best first, in the
+    /// same order as `titles`.
+    fn find_candidates(&self, titles: &[String], limit: usize) -> Result<Vec<Vec<Candidate>>>;
+}
+
+/// A possible match, with a score whose scale depends on the `Matcher`.
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub series: Series,
+    pub score: f64,
+    /// Which fields matched, e.g. "main_title", "normalized_titles",
+    /// "titles" (titles in the searched languages) or "titles.x-jat"
+    pub matched_fields: Vec<String>,
+}
+
+/// The outcome of applying `MatchThresholds` to a title's candidates.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Decision {
+    /// The top candidate is a match
+    Accepted,
+    /// There were no candidates at all
+    NoCandidates,
+    /// The top candidate's score is below `min_score`
+    BelowMinScore,
+    /// The top two candidates' scores are closer than `min_margin`
+    Ambiguous,
+}
+
+/// Rules for deciding whether the top candidate is a good enough match.
+/// Since scores aren't comparable between matchers, appropriate values
+/// depend on the `Matcher` used.
+#[derive(Debug, Clone, Copy)]
+pub struct MatchThresholds {
+    /// How many candidates to fetch per title
+    pub candidates: usize,
+    pub min_score: f64,
+    /// Minimum difference in score between the first and second candidates
+    pub min_margin: f64,
+}
+
+impl Default for MatchThresholds {
+    /// Accepts the top candidate, whatever its score
+    fn default() -> Self {
+        MatchThresholds {
+            candidates: 2,
+            min_score: 0.0,
+            min_margin: 0.0,
+        }
+    }
+}
+
+impl MatchThresholds {
+    /// Checks for settings that would make `decide` reject every title, or
+    /// silently ignore `min_margin`.
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: &str| -> Result<()> {
+            Err(ErrorKind::InvalidThresholds(reason.to_string()).into())
+        };
+
+        if self.candidates == 0 {
+            return invalid("at least 1 candidate is needed to match anything");
+        }
+
+        if self.candidates < 2 && self.min_margin > 0.0 {
+            return invalid("at least 2 candidates are needed to check the minimum margin");
+        }
+
+        Ok(())
+    }
+
+    /// Decides whether to accept the first of `candidates`, which are
+    /// expected to be sorted best first.
+    pub fn decide(&self, candidates: &[Candidate]) -> Decision {
+        let best = match candidates.first() {
+            Some(best) => best,
+            None => return Decision::NoCandidates,
+        };
+
+        if best.score < self.min_score {
+            return Decision::BelowMinScore;
+        }
+
+        match candidates.get(1) {
+            Some(second) if best.score - second.score < self.min_margin => Decision::Ambiguous,
+            _ => Decision::Accepted,
+        }
+    }
+}
+
+// Scores awarded for exact matches, mirroring the boosts used in
+// `elastic::Client::multi_search`. Token matches score at most 1.0.
+const NORMALIZED_MATCH_SCORE: f64 = 20.0;
+const MAIN_TITLE_MATCH_SCORE: f64 = 10.0;
+
+/// Matches titles against an in-memory index of series, without needing
+/// Elasticsearch. Scoring roughly follows the Elasticsearch query: exact
+/// matches (on main title or after normalization) rank highest, followed by
+/// the share of the title's tokens found in the series' titles, weighted by
+/// how rare each token is.
+///
+/// As with the `cjk` analyzer, Japanese text is split into overlapping
+/// character bigrams, and other text into words.
+pub struct MemoryMatcher {
+    series: Vec<Series>,
+    main_titles: HashMap<String, Vec<usize>>,
+    normalized_titles: HashMap<String, Vec<usize>>,
+    tokens: HashMap<String, Vec<usize>>,
+    romaji_tokens: HashMap<String, Vec<usize>>,
+}
+
+impl MemoryMatcher {
+    /// Indexes `series`, with token matching against titles in the given
+    /// languages. Kana titles are also matched against `x-jat` titles.
+    pub fn new<S>(series: Vec<Series>, languages: &[S]) -> Self
+        where S: AsRef<str>
+    {
+        let mut main_titles = HashMap::new();
+        let mut normalized_titles = HashMap::new();
+        let mut tokens = HashMap::new();
+        let mut romaji_tokens = HashMap::new();
+
+        for (i, s) in series.iter().enumerate() {
+            if let Some(ref main_title) = s.main_title {
+                add_posting(&mut main_titles, main_title.clone(), i);
+            }
+
+            for normalized in &s.normalized_titles {
+                add_posting(&mut normalized_titles, normalized.clone(), i);
+            }
+
+            for language in languages {
+                for title in s.titles.0.get(language.as_ref()).into_iter().flat_map(|t| t) {
+                    for token in tokenize(&normalize(title)) {
+                        add_posting(&mut tokens, token, i);
+                    }
+                }
+            }
+
+            for title in s.titles.0.get("x-jat").into_iter().flat_map(|t| t) {
+                for token in tokenize(&normalize(title)) {
+                    add_posting(&mut romaji_tokens, token, i);
+                }
+            }
+        }
+
+        MemoryMatcher {
+            series: series,
+            main_titles: main_titles,
+            normalized_titles: normalized_titles,
+            tokens: tokens,
+            romaji_tokens: romaji_tokens,
+        }
+    }
+
+    pub fn series(&self) -> &[Series] {
+        &self.series
+    }
+
+    pub fn into_series(self) -> Vec<Series> {
+        self.series
+    }
+
+    /// Indices of matching series along with their scores and matched
+    /// fields, best first.
+    fn rank(&self, title: &str) -> Vec<(usize, Score)> {
+        let normalized = normalize(title);
+        let mut scores: HashMap<usize, Score> = HashMap::new();
+
+        for &i in self.main_titles.get(title).into_iter().flat_map(|ids| ids) {
+            add_score(&mut scores, i, MAIN_TITLE_MATCH_SCORE, "main_title");
+        }
+
+        for &i in self.normalized_titles.get(&normalized).into_iter().flat_map(|ids| ids) {
+            add_score(&mut scores, i, NORMALIZED_MATCH_SCORE, "normalized_titles");
+        }
+
+        self.add_token_scores(&self.tokens, &tokenize(&normalized), "titles", &mut scores);
+
+        if romaji::has_kana(title) {
+            let romaji_tokens = tokenize(&romaji::normalized_romaji(title));
+            self.add_token_scores(&self.romaji_tokens,
+                                  &romaji_tokens,
+                                  "titles.x-jat",
+                                  &mut scores);
+        }
+
+        let mut ranked = scores.into_iter().collect::<Vec<_>>();
+
+        // Ties go to the lowest index, to keep results deterministic
+        ranked.sort_by(|a, b| {
+            (b.1).0.partial_cmp(&(a.1).0).unwrap_or(Ordering::Equal).then(a.0.cmp(&b.0))
+        });
+
+        ranked
+    }
+
+    fn add_token_scores(&self,
+                        index: &HashMap<String, Vec<usize>>,
+                        tokens: &[String],
+                        field: &'static str,
+                        scores: &mut HashMap<usize, Score>) {
+        let n = self.series.len() as f64;
+
+        let mut unique_tokens = tokens.iter().collect::<Vec<_>>();
+        unique_tokens.sort();
+        unique_tokens.dedup();
+
+        let weighted = unique_tokens.into_iter()
+            .map(|token| {
+                let postings = index.get(token);
+                let df = postings.map_or(0, |p| p.len()) as f64;
+                (postings, (1.0 + n / (1.0 + df)).ln())
+            })
+            .collect::<Vec<_>>();
+
+        let total_weight = weighted.iter().map(|&(_, w)| w).sum::<f64>();
+        if total_weight == 0.0 {
+            return;
+        }
+
+        for (postings, weight) in weighted {
+            for &i in postings.into_iter().flat_map(|p| p) {
+                add_score(scores, i, weight / total_weight, field);
+            }
+        }
+    }
+}
+
+// Total score, and the fields that contributed to it
+type Score = (f64, Vec<&'static str>);
+
+fn add_score(scores: &mut HashMap<usize, Score>, i: usize, score: f64, field: &'static str) {
+    let entry = scores.entry(i).or_insert((0.0, Vec::new()));
+    entry.0 += score;
+
+    if !entry.1.contains(&field) {
+        entry.1.push(field);
+    }
+}
+
+impl Matcher for MemoryMatcher {
+    fn find_candidates(&self, titles: &[String], limit: usize) -> Result<Vec<Vec<Candidate>>> {
+        let candidates = titles.iter()
+            .map(|title| {
+                self.rank(title)
+                    .into_iter()
+                    .take(limit)
+                    .map(|(i, (score, fields))| {
+                        Candidate {
+                            series: self.series[i].clone(),
+                            score: score,
+                            matched_fields: fields.into_iter().map(|f| f.to_string()).collect(),
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Ok(candidates)
+    }
+}
+
+fn add_posting(index: &mut HashMap<String, Vec<usize>>, key: String, i: usize) {
+    match index.entry(key) {
+        Entry::Occupied(mut o) => {
+            // Series are indexed in order, so duplicates are always adjacent
+            if o.get().last() != Some(&i) {
+                o.get_mut().push(i);
+            }
+        }
+        Entry::Vacant(v) => {
+            v.insert(vec![i]);
+        }
+    }
+}
+
+// Splits normalized text into ASCII words and bigrams of non-ASCII characters
+fn tokenize(s: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    let mut cjk = Vec::new();
+
+    for c in s.chars() {
+        if c.is_alphanumeric() && c.is_ascii() {
+            push_bigrams(&mut tokens, &mut cjk);
+            word.push(c);
+        } else if c.is_alphanumeric() {
+            push_word(&mut tokens, &mut word);
+            cjk.push(c);
+        } else {
+            push_word(&mut tokens, &mut word);
+            push_bigrams(&mut tokens, &mut cjk);
+        }
+    }
+
+    push_word(&mut tokens, &mut word);
+    push_bigrams(&mut tokens, &mut cjk);
+
+    tokens
+}
+
+fn push_word(tokens: &mut Vec<String>, word: &mut String) {
+    if !word.is_empty() {
+        tokens.push(::std::mem::replace(word, String::new()));
+    }
+}
+
+fn push_bigrams(tokens: &mut Vec<String>, chars: &mut Vec<char>) {
+    if chars.len() == 1 {
+        tokens.push(chars[0].to_string());
+    } else {
+        tokens.extend(chars.windows(2).map(|w| w.iter().cloned().collect::<String>()));
+    }
+
+    chars.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elastic::TitlesByLanguage;
+
+    fn candidate(id: &str, score: f64) -> Candidate {
+        Candidate {
+            series: Series {
+                id: id.to_string(),
+                main_title: None,
+                titles: TitlesByLanguage(HashMap::new()),
+                normalized_titles: Vec::new(),
+            },
+            score: score,
+            matched_fields: Vec::new(),
+        }
+    }
+
+    fn thresholds(candidates: usize, min_score: f64, min_margin: f64) -> MatchThresholds {
+        MatchThresholds {
+            candidates: candidates,
+            min_score: min_score,
+            min_margin: min_margin,
+        }
+    }
+
+    #[test]
+    fn rejects_too_few_candidates() {
+        assert!(thresholds(0, 0.0, 0.0).validate().is_err());
+        assert!(thresholds(1, 5.0, 1.0).validate().is_err());
+
+        assert!(thresholds(1, 5.0, 0.0).validate().is_ok());
+        assert!(thresholds(2, 5.0, 1.0).validate().is_ok());
+        assert!(MatchThresholds::default().validate().is_ok());
+    }
+
+    #[test]
+    fn decides_by_score_and_margin() {
+        let thresholds = thresholds(2, 5.0, 1.0);
+
+        assert_eq!(thresholds.decide(&[]), Decision::NoCandidates);
+        assert_eq!(thresholds.decide(&[candidate("1", 4.0)]), Decision::BelowMinScore);
+        assert_eq!(thresholds.decide(&[candidate("1", 6.0)]), Decision::Accepted);
+        assert_eq!(thresholds.decide(&[candidate("1", 6.0), candidate("2", 5.5)]),
+                   Decision::Ambiguous);
+        assert_eq!(thresholds.decide(&[candidate("1", 6.0), candidate("2", 4.0)]),
+                   Decision::Accepted);
+    }
+
+    fn series(id: &str, titles: &[(&str, &str)]) -> Series {
+        let mut by_language: HashMap<String, Vec<String>> = HashMap::new();
+        for &(language, title) in titles {
+            by_language.entry(language.to_string())
+                .or_insert_with(Vec::new)
+                .push(title.to_string());
+        }
+
+        let titles = TitlesByLanguage(by_language);
+
+        Series {
+            id: id.to_string(),
+            main_title: titles.main_title("ja"),
+            normalized_titles: titles.normalized(),
+            titles: titles,
+        }
+    }
+
+    fn matcher() -> MemoryMatcher {
+        let series = vec![series("9348",
+                                 &[("ja", "アイカツ! アイドルカツドウ!"),
+                                   ("x-jat", "Aikatsu! Idol Katsudou!"),
+                                   ("en", "Aikatsu! Idol Activities!")]),
+                          series("9541", &[("ja", "進撃の巨人"), ("x-jat", "Shingeki no Kyojin")]),
+                          series("1234", &[("ja", "巨人の星"), ("x-jat", "Kyojin no Hoshi")])];
+
+        MemoryMatcher::new(series, &["ja", "en"])
+    }
+
+    fn search(matcher: &MemoryMatcher, title: &str) -> Vec<Candidate> {
+        matcher.find_candidates(&[title.to_string()], 10).unwrap().remove(0)
+    }
+
+    fn ids(candidates: &[Candidate]) -> Vec<&str> {
+        candidates.iter().map(|c| c.series.id.as_str()).collect()
+    }
+
+    #[test]
+    fn ranks_exact_main_title_first() {
+        let candidates = search(&matcher(), "アイカツ! アイドルカツドウ!");
+
+        assert_eq!(ids(&candidates), vec!["9348"]);
+        assert_eq!(candidates[0].matched_fields,
+                   vec!["main_title", "normalized_titles", "titles", "titles.x-jat"]);
+        assert!(candidates[0].score >= MAIN_TITLE_MATCH_SCORE + NORMALIZED_MATCH_SCORE);
+    }
+
+    #[test]
+    fn matches_normalized_titles() {
+        // Fullwidth punctuation and an ideographic space
+        let candidates = search(&matcher(), "アイカツ！　アイドルカツドウ！");
+
+        assert_eq!(ids(&candidates), vec!["9348"]);
+        assert_eq!(candidates[0].matched_fields,
+                   vec!["normalized_titles", "titles", "titles.x-jat"]);
+        assert!(candidates[0].score >= NORMALIZED_MATCH_SCORE);
+        assert!(candidates[0].score < MAIN_TITLE_MATCH_SCORE + NORMALIZED_MATCH_SCORE);
+
+        // Normalized titles include other languages
+        let candidates = search(&matcher(), "AIKATSU! IDOL ACTIVITIES!");
+        assert_eq!(ids(&candidates), vec!["9348"]);
+        assert!(candidates[0].score >= NORMALIZED_MATCH_SCORE);
+    }
+
+    #[test]
+    fn scores_shared_tokens() {
+        let candidates = search(&matcher(), "劇場版 進撃巨人");
+
+        // Both share the "巨人" bigram, but only one shares "進撃"
+        assert_eq!(ids(&candidates), vec!["9541", "1234"]);
+        assert!(candidates.iter().all(|c| c.matched_fields == vec!["titles"]));
+        assert!(candidates[0].score > candidates[1].score);
+        assert!(candidates[0].score < 1.0 && candidates[1].score > 0.0);
+
+        assert!(search(&matcher(), "けいおん!").is_empty());
+    }
+
+    #[test]
+    fn matches_kana_titles_against_romaji() {
+        let candidates = search(&matcher(), "あいかつ!");
+
+        assert_eq!(ids(&candidates), vec!["9348"]);
+        assert_eq!(candidates[0].matched_fields, vec!["titles.x-jat"]);
+        assert!((candidates[0].score - 1.0).abs() < 1e-9);
+
+        // Only kana titles are transliterated
+        assert!(search(&matcher(), "Shingeki").is_empty());
+    }
+
+    #[test]
+    fn breaks_ties_by_order() {
+        for _ in 0..10 {
+            let matcher = MemoryMatcher::new(vec![series("20", &[("ja", "けいおん!")]),
+                                                  series("3", &[("ja", "けいおん!")]),
+                                                  series("100", &[("ja", "けいおん!")])],
+                                             &["ja"]);
+
+            let candidates = search(&matcher, "けいおん!");
+
+            assert_eq!(ids(&candidates), vec!["20", "3", "100"]);
+            assert!(candidates.iter().all(|c| c.score == candidates[0].score));
+        }
+    }
+
+    #[test]
+    fn limits_candidates_per_title() {
+        let titles = vec!["進撃の巨人".to_string(), "あいかつ!".to_string()];
+        let candidates = matcher().find_candidates(&titles, 1).unwrap();
+
+        assert_eq!(candidates.len(), 2);
+        assert_eq!(ids(&candidates[0]), vec!["9541"]);
+        assert_eq!(ids(&candidates[1]), vec!["9348"]);
+    }
+
+    #[test]
+    fn tokenizes_words_and_bigrams() {
+        assert_eq!(tokenize("aikatsu! idol katsudou!"),
+                   vec!["aikatsu", "idol", "katsudou"]);
+        assert_eq!(tokenize("進撃の巨人"), vec!["進撃", "撃の", "の巨", "巨人"]);
+        assert_eq!(tokenize("k-on!!けいおん"), vec!["k", "on", "けい", "いお", "おん"]);
+        assert_eq!(tokenize("星 2"), vec!["星", "2"]);
+    }
+}
@@ -0,0 +1,123 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Normalizes a title for matching, so that titles differing only in
+/// character width, punctuation variants or spacing compare equal. E.g.
+/// "アイカツ！　アイドルカツドウ！" and "アイカツ! アイドルカツドウ!" both
+/// become "アイカツ!アイドルカツドウ!".
+///
+/// This applies, in order:
+///
+/// * NFKC normalization (fullwidth to halfwidth ASCII, halfwidth to fullwidth
+///   kana, etc)
+/// * Lowercasing
+/// * Unification of wave dashes, hyphens/dashes, quotes and middle dots
+/// * Whitespace folding: runs of whitespace become a single space, and spaces
+///   next to non-ASCII characters (where spacing is inconsistent in Japanese
+///   titles) are dropped entirely
+pub fn normalize(title: &str) -> String {
+    let unified = title.nfkc()
+        .flat_map(|c| c.to_lowercase())
+        .map(unify_punctuation)
+        .collect::<String>();
+
+    fold_whitespace(&unified)
+}
+
+fn unify_punctuation(c: char) -> char {
+    match c {
+        // Wave dashes and tildes
+        '\u{301c}' | '\u{3030}' | '\u{223c}' | '\u{ff5e}' => '~',
+        // Hyphens and dashes (but not the katakana long vowel mark)
+        '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2015}' |
+        '\u{2212}' => '-',
+        '\u{2018}' | '\u{2019}' | '\u{2032}' => '\'',
+        '\u{201c}' | '\u{201d}' | '\u{2033}' => '"',
+        // Middle dots are used as word separators
+        '\u{30fb}' | '\u{00b7}' | '\u{2022}' => ' ',
+        _ => c,
+    }
+}
+
+fn fold_whitespace(s: &str) -> String {
+    let words = s.split_whitespace().collect::<Vec<_>>();
+    let mut out = String::with_capacity(s.len());
+
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            let prev_is_ascii = out.chars().last().map_or(true, |c| c.is_ascii());
+            let next_is_ascii = word.chars().next().map_or(true, |c| c.is_ascii());
+
+            if prev_is_ascii && next_is_ascii {
+                out.push(' ');
+            }
+        }
+
+        out.push_str(word);
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_doc_example() {
+        assert_eq!(normalize("アイカツ！　アイドルカツドウ！"), "アイカツ!アイドルカツドウ!");
+        assert_eq!(normalize("アイカツ! アイドルカツドウ!"), "アイカツ!アイドルカツドウ!");
+    }
+
+    #[test]
+    fn folds_character_width() {
+        assert_eq!(normalize("ＡＢＣ１２３"), "abc123");
+        assert_eq!(normalize("ｱｲｶﾂ"), "アイカツ");
+        assert_eq!(normalize("ｶﾞﾝﾀﾞﾑ"), "ガンダム");
+        assert_eq!(normalize("ｹｲｵﾝ!!"), normalize("ケイオン!!"));
+    }
+
+    #[test]
+    fn lowercases() {
+        assert_eq!(normalize("K-ON!"), "k-on!");
+        assert_eq!(normalize("Shingeki no Kyojin"), "shingeki no kyojin");
+        // NFKC first, so compatibility characters are lowercased too
+        assert_eq!(normalize("ＤＡＲＫＥＲ ＴＨＡＮ ＢＬＡＣＫ"), "darker than black");
+        assert_eq!(normalize("Ⅱ"), "ii");
+    }
+
+    #[test]
+    fn unifies_wave_dashes_and_dashes() {
+        for title in &["ひだまり〜スケッチ", "ひだまり～スケッチ", "ひだまり∼スケッチ", "ひだまり〰スケッチ"] {
+            assert_eq!(normalize(title), "ひだまり~スケッチ");
+        }
+
+        assert_eq!(normalize("Re:Zero – Starting Life"), "re:zero - starting life");
+        assert_eq!(normalize("A—B‐C"), "a-b-c");
+
+        // The long vowel mark isn't a dash
+        assert_eq!(normalize("ラブライブ！サンシャイン‼ー"), "ラブライブ!サンシャイン!!ー");
+    }
+
+    #[test]
+    fn unifies_quotes() {
+        assert_eq!(normalize("Kiki’s “Delivery”"), "kiki's \"delivery\"");
+    }
+
+    #[test]
+    fn treats_middle_dots_as_spaces() {
+        // Spaces next to non-ASCII characters are dropped too
+        assert_eq!(normalize("ソードアート・オンライン"), "ソードアートオンライン");
+        assert_eq!(normalize("ｿｰﾄﾞｱｰﾄ･ｵﾝﾗｲﾝ"), "ソードアートオンライン");
+        assert_eq!(normalize("Fate·Zero"), "fate zero");
+        assert_eq!(normalize("Fate・Zero"), "fate zero");
+    }
+
+    #[test]
+    fn folds_whitespace() {
+        assert_eq!(normalize("  Tokyo   Ghoul\t"), "tokyo ghoul");
+        assert_eq!(normalize("Tokyo\u{3000}Ghoul"), "tokyo ghoul");
+        assert_eq!(normalize("進撃の巨人 Season 2"), "進撃の巨人season 2");
+        assert_eq!(normalize("進撃の 巨人"), "進撃の巨人");
+        assert_eq!(normalize(" \t "), "");
+    }
+}
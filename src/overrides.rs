@@ -0,0 +1,243 @@
+use csv;
+use error::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::path::Path;
+
+/// A manual decision for a ClubDAM title, taking precedence over matching.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Override {
+    /// Always match the AniDB series with this ID
+    Match(u32),
+    /// Never match any AniDB series
+    NoMatch,
+    /// Leave the ClubDAM series out entirely
+    Exclude,
+}
+
+impl Override {
+    /// The action as written in the overrides file.
+    pub fn action(&self) -> &'static str {
+        match *self {
+            Override::Match(_) => "match",
+            Override::NoMatch => "no_match",
+            Override::Exclude => "exclude",
+        }
+    }
+}
+
+/// Curated overrides for ClubDAM titles that are matched wrongly, read from a
+/// CSV file with a header row. Leading and trailing whitespace is ignored, both
+/// in the file and in the titles being looked up. E.g.:
+///
+/// ```text
+/// clubdam_title,action,anidb_id
+/// 進撃の巨人 Season2,match,12345
+/// アイカツ,no_match,
+/// テスト,exclude,
+/// ```
+#[derive(Debug, Default)]
+pub struct Overrides(HashMap<String, Override>);
+
+impl Overrides {
+    pub fn from_file<P>(path: P) -> Result<Self>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let reader = csv::Reader::from_file(path)
+            .chain_err(|| format!("failed to open overrides file {}", path.display()))?;
+
+        Overrides::from_csv(reader)
+    }
+
+    pub fn from_reader<R>(reader: R) -> Result<Self>
+        where R: Read
+    {
+        Overrides::from_csv(csv::Reader::from_reader(reader))
+    }
+
+    fn from_csv<R>(reader: csv::Reader<R>) -> Result<Self>
+        where R: Read
+    {
+        let mut reader = reader.has_headers(true).flexible(true);
+        let mut overrides = HashMap::new();
+
+        for (i, record) in reader.records().enumerate() {
+            // Line numbers are 1-based, after the header
+            let line_num = i as u32 + 2;
+
+            let invalid = |reason: String| -> Error {
+                ErrorKind::InvalidOverride(line_num, reason).into()
+            };
+
+            let row = record.chain_err(|| invalid("unreadable row".to_string()))?;
+            let field = |n: usize| row.get(n).map(|f| f.trim()).unwrap_or("");
+
+            let title = field(0);
+            if title.is_empty() {
+                return Err(invalid("missing ClubDAM title".to_string()));
+            }
+
+            let action = match field(1) {
+                "match" => {
+                    let id = field(2);
+                    match id.parse::<u32>() {
+                        Ok(id) => Override::Match(id),
+                        Err(_) => return Err(invalid(format!("invalid AniDB ID \"{}\"", id))),
+                    }
+                }
+                "no_match" => Override::NoMatch,
+                "exclude" => Override::Exclude,
+                other => {
+                    return Err(invalid(format!("unknown action \"{}\" (expected \"match\", \
+                                                \"no_match\" or \"exclude\")",
+                                               other)));
+                }
+            };
+
+            overrides.insert(title.to_string(), action);
+        }
+
+        Ok(Overrides(overrides))
+    }
+
+    pub fn get(&self, clubdam_title: &str) -> Option<Override> {
+        self.0.get(clubdam_title.trim()).cloned()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Adds an override, and appends it to the overrides file at `path`
+    /// (creating the file, with a header row, if it doesn't exist yet).
+    pub fn append<P>(&mut self, path: P, clubdam_title: &str, action: Override) -> Result<()>
+        where P: AsRef<Path>
+    {
+        let path = path.as_ref();
+        let clubdam_title = clubdam_title.trim();
+        let write_err = || format!("failed to write to overrides file {}", path.display());
+
+        let file = OpenOptions::new().create(true).append(true).open(path).chain_err(&write_err)?;
+        let is_new = file.metadata().chain_err(&write_err)?.len() == 0;
+
+        let mut writer = csv::Writer::from_writer(file);
+
+        if is_new {
+            writer.write(["clubdam_title", "action", "anidb_id"].iter().map(|h| *h))
+                .chain_err(&write_err)?;
+        }
+
+        let id = match action {
+            Override::Match(id) => id.to_string(),
+            _ => String::new(),
+        };
+
+        writer.write([clubdam_title, action.action(), &id].iter().map(|f| *f))
+            .chain_err(&write_err)?;
+        writer.flush().chain_err(&write_err)?;
+
+        self.0.insert(clubdam_title.to_string(), action);
+        Ok(())
+    }
+
+    /// Checks that every AniDB ID referenced by a `match` override exists in
+    /// `anidb_ids` (the IDs from the parsed dump).
+    pub fn validate(&self, anidb_ids: &HashSet<u32>) -> Result<()> {
+        let mut unknown = self.0
+            .values()
+            .filter_map(|o| match *o {
+                Override::Match(id) if !anidb_ids.contains(&id) => Some(id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if unknown.is_empty() {
+            Ok(())
+        } else {
+            unknown.sort();
+            Err(ErrorKind::UnknownOverrideIds(unknown).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use time;
+
+    const OVERRIDES: &'static str = "clubdam_title,action,anidb_id
+進撃の巨人 Season2,match,12345
+ アイカツ ,no_match,
+テスト,exclude
+";
+
+    #[test]
+    fn reads_each_action() {
+        let overrides = Overrides::from_reader(OVERRIDES.as_bytes()).unwrap();
+
+        assert_eq!(overrides.get("進撃の巨人 Season2"), Some(Override::Match(12345)));
+        assert_eq!(overrides.get("アイカツ"), Some(Override::NoMatch));
+        assert_eq!(overrides.get("テスト"), Some(Override::Exclude));
+        assert_eq!(overrides.get("進撃の巨人"), None);
+    }
+
+    #[test]
+    fn ignores_surrounding_whitespace_in_lookups() {
+        let overrides = Overrides::from_reader(OVERRIDES.as_bytes()).unwrap();
+
+        assert_eq!(overrides.get(" アイカツ"), Some(Override::NoMatch));
+        assert_eq!(overrides.get("進撃の巨人 Season2 "), Some(Override::Match(12345)));
+    }
+
+    #[test]
+    fn rejects_invalid_rows() {
+        for input in &["clubdam_title,action,anidb_id\nアイカツ,match,abc\n",
+                       "clubdam_title,action,anidb_id\nアイカツ,ignore,\n",
+                       "clubdam_title,action,anidb_id\n,no_match,\n"] {
+            let err = Overrides::from_reader(input.as_bytes()).unwrap_err();
+
+            match *err.kind() {
+                ErrorKind::InvalidOverride(line_num, _) => assert_eq!(line_num, 2),
+                ref kind => panic!("unexpected error: {}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn validates_ids_against_dump() {
+        let overrides = Overrides::from_reader(OVERRIDES.as_bytes()).unwrap();
+
+        let mut ids = HashSet::new();
+        ids.insert(1);
+
+        match *overrides.validate(&ids).unwrap_err().kind() {
+            ErrorKind::UnknownOverrideIds(ref unknown) => assert_eq!(*unknown, vec![12345]),
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        ids.insert(12345);
+        assert!(overrides.validate(&ids).is_ok());
+    }
+
+    #[test]
+    fn appends_trimmed_titles() {
+        let path = env::temp_dir().join(format!("overrides_test_{}.csv", time::precise_time_ns()));
+        let _ = fs::remove_file(&path);
+
+        let mut overrides = Overrides::default();
+        overrides.append(&path, " アイカツ ", Override::NoMatch).unwrap();
+        overrides.append(&path, "進撃の巨人", Override::Match(1)).unwrap();
+
+        assert_eq!(overrides.get("アイカツ"), Some(Override::NoMatch));
+
+        let read_back = Overrides::from_file(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read_back.get("アイカツ"), Some(Override::NoMatch));
+        assert_eq!(read_back.get("進撃の巨人"), Some(Override::Match(1)));
+    }
+}
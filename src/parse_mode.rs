@@ -0,0 +1,268 @@
+use Title;
+use error::*;
+use std::fmt;
+
+/// How to handle lines in the titles dump that fail to parse.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ParseMode {
+    /// Fail on the first bad line
+    Strict,
+    /// Skip bad lines, failing at the end only if the ratio of bad lines to
+    /// total lines is greater than `max_bad_ratio`
+    Lenient { max_bad_ratio: f64 },
+}
+
+/// A line that was skipped in lenient mode.
+#[derive(Debug, Clone)]
+pub struct BadLine {
+    pub line_number: u32,
+    pub raw: String,
+    pub reason: BadLineReason,
+}
+
+/// Why a line failed to parse, from the error wrapped in
+/// `ErrorKind::InvalidLine`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum BadLineReason {
+    /// The anime ID isn't a number (`ErrorKind::InvalidId`)
+    InvalidId(String),
+    /// Fields or attributes are missing, or the title isn't in a valid anime
+    /// (`ErrorKind::InvalidParse`)
+    InvalidParse,
+    /// Any other error, with its message
+    Other(String),
+}
+
+impl BadLineReason {
+    fn from_error(e: &Error) -> Self {
+        // The underlying cause, e.g. `InvalidId`
+        let cause = e.1.next_error.as_ref().and_then(|c| c.downcast_ref::<Error>());
+
+        match cause.map(|c| c.kind()) {
+            Some(&ErrorKind::InvalidId(ref id, _)) => BadLineReason::InvalidId(id.clone()),
+            Some(&ErrorKind::InvalidParse(_)) => BadLineReason::InvalidParse,
+            _ => BadLineReason::Other(e.iter().nth(1).unwrap_or(e).to_string()),
+        }
+    }
+}
+
+impl fmt::Display for BadLineReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BadLineReason::InvalidId(ref id) => write!(f, "invalid anime ID \"{}\"", id),
+            BadLineReason::InvalidParse => write!(f, "missing or invalid fields"),
+            BadLineReason::Other(ref message) => write!(f, "{}", message),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ParseReport {
+    pub parsed: usize,
+    pub bad_lines: Vec<BadLine>,
+}
+
+impl ParseReport {
+    pub fn total(&self) -> usize {
+        self.parsed + self.bad_lines.len()
+    }
+
+    pub fn bad_ratio(&self) -> f64 {
+        if self.total() == 0 {
+            0.0
+        } else {
+            self.bad_lines.len() as f64 / self.total() as f64
+        }
+    }
+}
+
+/// Wraps an iterator of parsed titles, applying a `ParseMode` to its errors.
+///
+/// Only line-level errors (`ErrorKind::InvalidLine`) are skipped in lenient
+/// mode. Anything else (e.g. I/O errors) is still returned, since we can't
+/// continue reading past it.
+pub struct CheckedTitles<I> {
+    inner: I,
+    mode: ParseMode,
+    report: ParseReport,
+    done: bool,
+}
+
+impl<I> CheckedTitles<I>
+    where I: Iterator<Item = Result<Title>>
+{
+    pub fn new(inner: I, mode: ParseMode) -> Self {
+        CheckedTitles {
+            inner: inner,
+            mode: mode,
+            report: ParseReport::default(),
+            done: false,
+        }
+    }
+
+    pub fn report(&self) -> &ParseReport {
+        &self.report
+    }
+}
+
+impl<I> Iterator for CheckedTitles<I>
+    where I: Iterator<Item = Result<Title>>
+{
+    type Item = Result<Title>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            match self.inner.next() {
+                Some(Ok(title)) => {
+                    self.report.parsed += 1;
+                    return Some(Ok(title));
+                }
+
+                Some(Err(e)) => {
+                    let (line_number, raw) = match (self.mode, e.kind()) {
+                        (ParseMode::Lenient { .. }, &ErrorKind::InvalidLine(n, ref raw)) => {
+                            (n, raw.clone())
+                        }
+                        _ => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    };
+
+                    self.report.bad_lines.push(BadLine {
+                        line_number: line_number,
+                        raw: raw,
+                        reason: BadLineReason::from_error(&e),
+                    });
+                }
+
+                None => {
+                    self.done = true;
+
+                    if let ParseMode::Lenient { max_bad_ratio } = self.mode {
+                        if self.report.bad_ratio() > max_bad_ratio {
+                            let kind = ErrorKind::TooManyBadLines(self.report.bad_lines.len(),
+                                                                  self.report.total(),
+                                                                  max_bad_ratio);
+                            return Some(Err(kind.into()));
+                        }
+                    }
+
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TitleIterator;
+    use std::io;
+    use std::io::Cursor;
+
+    // Three good lines and two bad ones
+    const DUMP: &'static [u8] = b"#\n#\n#\n\
+1|1|x-jat|Seikai no Monshou\n\
+abc|1|x-jat|Bad ID\n\
+2|1|x-jat|Seikai no Senki\n\
+3|1\n\
+4|1|x-jat|Seikai no Danshou\n";
+
+    fn titles() -> TitleIterator {
+        TitleIterator::from_reader(Cursor::new(DUMP)).unwrap()
+    }
+
+    fn lenient(max_bad_ratio: f64) -> ParseMode {
+        ParseMode::Lenient { max_bad_ratio: max_bad_ratio }
+    }
+
+    #[test]
+    fn records_skipped_lines() {
+        let mut checked = CheckedTitles::new(titles(), lenient(0.5));
+        let ids = checked.by_ref().map(|t| t.unwrap().id).collect::<Vec<_>>();
+
+        assert_eq!(ids, vec![1, 2, 4]);
+
+        let report = checked.report();
+        assert_eq!((report.parsed, report.total()), (3, 5));
+        assert_eq!(report.bad_ratio(), 0.4);
+
+        let bad_lines = report.bad_lines
+            .iter()
+            .map(|b| (b.line_number, b.raw.as_str(), b.reason.clone()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(bad_lines,
+                   vec![(4, "abc|1|x-jat|Bad ID", BadLineReason::InvalidId("abc".to_string())),
+                        (6, "3|1", BadLineReason::InvalidParse)]);
+
+        assert_eq!(report.bad_lines[0].reason.to_string(), "invalid anime ID \"abc\"");
+    }
+
+    #[test]
+    fn fails_when_too_many_lines_are_bad() {
+        let mut checked = CheckedTitles::new(titles(), lenient(0.3));
+        let results = checked.by_ref().collect::<Vec<_>>();
+
+        // Good titles come first, and the error only once the ratio is known
+        assert_eq!(results.len(), 4);
+        assert!(results[..3].iter().all(|r| r.is_ok()));
+
+        match *results[3].as_ref().unwrap_err().kind() {
+            ErrorKind::TooManyBadLines(bad, total, max_ratio) => {
+                assert_eq!((bad, total, max_ratio), (2, 5, 0.3));
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        assert!(checked.next().is_none());
+        assert_eq!(checked.report().bad_lines.len(), 2);
+    }
+
+    #[test]
+    fn allows_exactly_the_maximum_ratio() {
+        let checked = CheckedTitles::new(titles(), lenient(0.4));
+        assert!(checked.collect::<Result<Vec<_>>>().is_ok());
+
+        let no_titles = CheckedTitles::new(Vec::<Result<Title>>::new().into_iter(), lenient(0.0));
+        assert!(no_titles.collect::<Result<Vec<_>>>().unwrap().is_empty());
+    }
+
+    #[test]
+    fn strict_mode_stops_at_first_bad_line() {
+        let mut checked = CheckedTitles::new(titles(), ParseMode::Strict);
+
+        assert_eq!(checked.next().unwrap().unwrap().id, 1);
+
+        match *checked.next().unwrap().unwrap_err().kind() {
+            ErrorKind::InvalidLine(line_num, ref raw) => {
+                assert_eq!((line_num, raw.as_str()), (4, "abc|1|x-jat|Bad ID"));
+            }
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        assert!(checked.next().is_none());
+        assert!(checked.report().bad_lines.is_empty());
+    }
+
+    #[test]
+    fn returns_other_errors_in_lenient_mode() {
+        let io_error = io::Error::new(io::ErrorKind::Other, "disk on fire");
+        let good = titles().next().unwrap();
+        let inner = vec![Err(Error::from(io_error)), good];
+        let mut checked = CheckedTitles::new(inner.into_iter(), lenient(1.0));
+
+        match *checked.next().unwrap().unwrap_err().kind() {
+            ErrorKind::Io(_) => {}
+            ref kind => panic!("unexpected error: {}", kind),
+        }
+
+        assert!(checked.next().is_none());
+    }
+}
@@ -0,0 +1,272 @@
+use conflicts::Conflicts;
+use csv;
+use error::*;
+use matcher::Candidate;
+use serde_json;
+use std::fs::File;
+use std::path::Path;
+
+/// How a ClubDAM series ended up matched (or not).
+#[derive(Debug, PartialEq, Clone, Copy, Serialize)]
+pub enum MatchStatus {
+    #[serde(rename = "matched")]
+    Matched,
+    #[serde(rename = "no_candidates")]
+    NoCandidates,
+    #[serde(rename = "below_min_score")]
+    BelowMinScore,
+    #[serde(rename = "ambiguous")]
+    Ambiguous,
+    /// Rejected by the conflict policy
+    #[serde(rename = "conflict")]
+    Conflict,
+    #[serde(rename = "override_match")]
+    OverrideMatch,
+    #[serde(rename = "override_no_match")]
+    OverrideNoMatch,
+    #[serde(rename = "excluded")]
+    Excluded,
+}
+
+impl MatchStatus {
+    pub fn name(&self) -> &'static str {
+        use self::MatchStatus::*;
+        match *self {
+            Matched => "matched",
+            NoCandidates => "no_candidates",
+            BelowMinScore => "below_min_score",
+            Ambiguous => "ambiguous",
+            Conflict => "conflict",
+            OverrideMatch => "override_match",
+            OverrideNoMatch => "override_no_match",
+            Excluded => "excluded",
+        }
+    }
+}
+
+/// A candidate AniDB series for a ClubDAM title.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportCandidate {
+    pub anidb_id: String,
+    pub main_title: Option<String>,
+    pub score: f64,
+    pub matched_fields: Vec<String>,
+}
+
+impl<'a> From<&'a Candidate> for ReportCandidate {
+    fn from(candidate: &'a Candidate) -> Self {
+        ReportCandidate {
+            anidb_id: candidate.series.id.clone(),
+            main_title: candidate.series.main_title.clone(),
+            score: candidate.score,
+            matched_fields: candidate.matched_fields.clone(),
+        }
+    }
+}
+
+/// The outcome of matching a single ClubDAM series.
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportEntry {
+    pub clubdam_title: String,
+    pub status: MatchStatus,
+    /// The chosen AniDB series, if any
+    pub chosen: Option<ReportCandidate>,
+    /// All other candidates considered, best first
+    pub alternatives: Vec<ReportCandidate>,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct MatchReport {
+    pub entries: Vec<ReportEntry>,
+    pub clubdam_titles_not_in_anidb: Vec<String>,
+    pub conflicts: Conflicts,
+}
+
+impl MatchReport {
+    pub fn write_json<P>(&self, path: P) -> Result<()>
+        where P: AsRef<Path>
+    {
+        let mut file = File::create(path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        Ok(())
+    }
+
+    /// Writes one row per ClubDAM series. Alternatives are written as
+    /// `id:score` pairs separated by ";".
+    pub fn write_csv<P>(&self, path: P) -> Result<()>
+        where P: AsRef<Path>
+    {
+        let mut writer = csv::Writer::from_file(path).chain_err(|| "failed to create CSV report")?;
+
+        let header = ["clubdam_title",
+                      "status",
+                      "anidb_id",
+                      "anidb_main_title",
+                      "score",
+                      "matched_fields",
+                      "alternatives"];
+
+        writer.write(header.iter().map(|h| *h)).chain_err(|| "failed to write CSV report")?;
+
+        for entry in &self.entries {
+            let (id, main_title, score, fields) = match entry.chosen {
+                Some(ref c) => {
+                    (c.anidb_id.clone(),
+                     c.main_title.clone().unwrap_or_else(String::new),
+                     c.score.to_string(),
+                     c.matched_fields.join(";"))
+                }
+                None => (String::new(), String::new(), String::new(), String::new()),
+            };
+
+            let alternatives = entry.alternatives
+                .iter()
+                .map(|c| format!("{}:{}", c.anidb_id, c.score))
+                .collect::<Vec<_>>()
+                .join(";");
+
+            let row = [entry.clubdam_title.clone(),
+                       entry.status.name().to_string(),
+                       id,
+                       main_title,
+                       score,
+                       fields,
+                       alternatives];
+
+            writer.write(row.iter().map(|f| f.as_str()))
+                .chain_err(|| "failed to write CSV report")?;
+        }
+
+        writer.flush().chain_err(|| "failed to write CSV report")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::io::Read;
+    use time;
+
+    fn candidate(id: &str, main_title: &str, score: f64, fields: &[&str]) -> ReportCandidate {
+        ReportCandidate {
+            anidb_id: id.to_string(),
+            main_title: Some(main_title.to_string()),
+            score: score,
+            matched_fields: fields.iter().map(|f| f.to_string()).collect(),
+        }
+    }
+
+    fn report() -> MatchReport {
+        MatchReport {
+            entries: vec![ReportEntry {
+                              clubdam_title: "魔女の宅急便".to_string(),
+                              status: MatchStatus::Matched,
+                              chosen: Some(candidate("1", "Kiki's \"Delivery\", Service", 31.5,
+                                                     &["main_title", "normalized_titles"])),
+                              alternatives: vec![candidate("2", "Majo", 0.25, &["titles"])],
+                          },
+                          ReportEntry {
+                              clubdam_title: "進撃の巨人, Season 2".to_string(),
+                              status: MatchStatus::Ambiguous,
+                              chosen: None,
+                              alternatives: vec![candidate("9541", "進撃の巨人", 0.5, &["titles"]),
+                                                 candidate("12345", "\"Shingeki\"", 0.5,
+                                                           &["titles"])],
+                          },
+                          ReportEntry {
+                              clubdam_title: "カラオケ".to_string(),
+                              status: MatchStatus::NoCandidates,
+                              chosen: None,
+                              alternatives: Vec::new(),
+                          }],
+            clubdam_titles_not_in_anidb: vec!["進撃の巨人, Season 2".to_string(),
+                                              "カラオケ".to_string()],
+            conflicts: Conflicts::default(),
+        }
+    }
+
+    // Writes the report to a temporary file, returning its contents
+    fn write<F>(extension: &str, write_to: F) -> String
+        where F: FnOnce(&Path) -> Result<()>
+    {
+        let path = env::temp_dir()
+            .join(format!("report_test_{}.{}", time::precise_time_ns(), extension));
+        write_to(&path).unwrap();
+
+        let mut contents = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut contents).unwrap();
+        fs::remove_file(&path).unwrap();
+        contents
+    }
+
+    #[test]
+    fn writes_json() {
+        let json = write("json", |path| report().write_json(path));
+        let report = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+
+        assert_eq!(report["entries"][0],
+                   json!({
+                       "clubdam_title": "魔女の宅急便",
+                       "status": "matched",
+                       "chosen": {
+                           "anidb_id": "1",
+                           "main_title": "Kiki's \"Delivery\", Service",
+                           "score": 31.5,
+                           "matched_fields": ["main_title", "normalized_titles"]
+                       },
+                       "alternatives": [{
+                           "anidb_id": "2",
+                           "main_title": "Majo",
+                           "score": 0.25,
+                           "matched_fields": ["titles"]
+                       }]
+                   }));
+
+        assert_eq!(report["entries"][1]["status"], json!("ambiguous"));
+        assert_eq!(report["entries"][1]["chosen"], json!(null));
+        assert_eq!(report["entries"][1]["alternatives"][1]["main_title"],
+                   json!("\"Shingeki\""));
+
+        assert_eq!(report["entries"][2]["status"], json!("no_candidates"));
+        assert_eq!(report["entries"][2]["alternatives"], json!([]));
+
+        assert_eq!(report["clubdam_titles_not_in_anidb"],
+                   json!(["進撃の巨人, Season 2", "カラオケ"]));
+        assert_eq!(report["conflicts"], json!({ "many_to_one": [], "ties": [] }));
+    }
+
+    #[test]
+    fn writes_csv() {
+        let contents = write("csv", |path| report().write_csv(path));
+
+        // Fields with commas or quotes are quoted, with quotes doubled
+        assert!(contents.contains("\"Kiki's \"\"Delivery\"\", Service\""));
+        assert!(contents.contains("\"進撃の巨人, Season 2\""));
+
+        let rows = csv::Reader::from_string(contents)
+            .has_headers(false)
+            .records()
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .unwrap();
+
+        assert_eq!(rows,
+                   vec![vec!["clubdam_title",
+                             "status",
+                             "anidb_id",
+                             "anidb_main_title",
+                             "score",
+                             "matched_fields",
+                             "alternatives"],
+                        vec!["魔女の宅急便",
+                             "matched",
+                             "1",
+                             "Kiki's \"Delivery\", Service",
+                             "31.5",
+                             "main_title;normalized_titles",
+                             "2:0.25"],
+                        vec!["進撃の巨人, Season 2", "ambiguous", "", "", "", "", "9541:0.5;12345:0.5"],
+                        vec!["カラオケ", "no_candidates", "", "", "", "", ""]]);
+    }
+}
@@ -0,0 +1,306 @@
+use normalize::normalize;
+use unicode_normalization::UnicodeNormalization;
+
+/// Transliterates kana to Hepburn romaji, following the conventions used in
+/// AniDB's `x-jat` titles (long vowels are spelled out, e.g. "おう" is "ou"
+/// and "ラーメン" is "raamen", and "を" is "wo"). Anything that isn't kana
+/// (kanji, ASCII, punctuation) is passed through unchanged, so only titles
+/// written entirely in kana transliterate fully.
+///
+/// The output is lowercase, e.g. "あいかつ! あいどるかつどう!" becomes
+/// "aikatsu! aidorukatsudou!".
+pub fn to_romaji(s: &str) -> String {
+    let kana = s.chars().map(katakana_to_hiragana).collect::<Vec<char>>();
+    let mut out = String::with_capacity(s.len() * 2);
+
+    let mut i = 0;
+    while i < kana.len() {
+        let c = kana[i];
+        let next = kana.get(i + 1).cloned();
+
+        match c {
+            // Small tsu doubles the following consonant ("tch" before "ch")
+            'っ' => {
+                if let Some(syllable) = next.and_then(|n| syllable(n)) {
+                    if syllable.starts_with("ch") {
+                        out.push('t');
+                    } else if let Some(consonant) = syllable.chars().next() {
+                        if !is_vowel(consonant) {
+                            out.push(consonant);
+                        }
+                    }
+                }
+            }
+
+            // Long vowel mark repeats the previous vowel
+            'ー' => {
+                match out.chars().last() {
+                    Some(v) if is_vowel(v) => out.push(v),
+                    _ => out.push(c),
+                }
+            }
+
+            // "n'" before a vowel or "y" to keep e.g. "ren'ai" distinct from "renai"
+            'ん' => {
+                out.push('n');
+
+                let needs_apostrophe = next.and_then(|n| syllable(n))
+                    .and_then(|s| s.chars().next())
+                    .map_or(false, |first| is_vowel(first) || first == 'y');
+
+                if needs_apostrophe {
+                    out.push('\'');
+                }
+            }
+
+            _ => {
+                if let Some(digraph) = next.and_then(|n| digraph(c, n)) {
+                    out.push_str(&digraph);
+                    i += 2;
+                    continue;
+                }
+
+                match syllable(c) {
+                    Some(syllable) => out.push_str(syllable),
+                    None => out.push(c),
+                }
+            }
+        }
+
+        i += 1;
+    }
+
+    out
+}
+
+/// Transliterates a title for matching against `x-jat` titles, normalizing
+/// it before and after. Only NFKC is applied before transliterating (so that
+/// halfwidth kana become fullwidth), since `normalize` drops spaces next to
+/// kana: "あいかつ! あいどるかつどう!" would otherwise become
+/// "aikatsu!aidorukatsudou!" instead of "aikatsu! aidorukatsudou!".
+pub fn normalized_romaji(title: &str) -> String {
+    normalize(&to_romaji(&title.nfkc().collect::<String>()))
+}
+
+/// Whether the string contains any kana, i.e. whether `to_romaji` would
+/// change it.
+pub fn has_kana(s: &str) -> bool {
+    s.chars().any(|c| syllable(katakana_to_hiragana(c)).is_some())
+}
+
+fn katakana_to_hiragana(c: char) -> char {
+    // Katakana "ァ" through "ヶ" are at a fixed offset from their hiragana
+    if c >= '\u{30a1}' && c <= '\u{30f6}' {
+        ::std::char::from_u32(c as u32 - 0x60).unwrap_or(c)
+    } else {
+        c
+    }
+}
+
+fn is_vowel(c: char) -> bool {
+    match c {
+        'a' | 'i' | 'u' | 'e' | 'o' => true,
+        _ => false,
+    }
+}
+
+// Two-kana combinations: "きゃ" (kya), "しゅ" (shu), "ふぁ" (fa), etc
+fn digraph(c: char, small: char) -> Option<String> {
+    let vowel = match small {
+        'ゃ' => "a",
+        'ゅ' => "u",
+        'ょ' => "o",
+        'ぁ' => "a",
+        'ぃ' => "i",
+        'ぅ' => "u",
+        'ぇ' => "e",
+        'ぉ' => "o",
+        _ => return None,
+    };
+
+    let is_y = match small {
+        'ゃ' | 'ゅ' | 'ょ' => true,
+        _ => false,
+    };
+
+    let consonant = match (c, is_y) {
+        ('き', true) => "ky",
+        ('ぎ', true) => "gy",
+        ('し', true) => "sh",
+        ('じ', true) => "j",
+        ('ち', true) => "ch",
+        ('ぢ', true) => "j",
+        ('に', true) => "ny",
+        ('ひ', true) => "hy",
+        ('び', true) => "by",
+        ('ぴ', true) => "py",
+        ('み', true) => "my",
+        ('り', true) => "ry",
+
+        // Small vowels extend the sounds available for loanwords
+        ('し', false) if small == 'ぇ' => "sh",
+        ('じ', false) if small == 'ぇ' => "j",
+        ('ち', false) if small == 'ぇ' => "ch",
+        ('つ', false) => "ts",
+        ('て', false) => "t",
+        ('で', false) => "d",
+        ('と', false) if small == 'ぅ' => "t",
+        ('ど', false) if small == 'ぅ' => "d",
+        ('ふ', false) => "f",
+        ('ゔ', false) => "v",
+        ('う', false) => "w",
+        _ => return None,
+    };
+
+    Some(format!("{}{}", consonant, vowel))
+}
+
+fn syllable(c: char) -> Option<&'static str> {
+    let romaji = match c {
+        'あ' => "a",
+        'い' => "i",
+        'う' => "u",
+        'え' => "e",
+        'お' => "o",
+        'か' => "ka",
+        'き' => "ki",
+        'く' => "ku",
+        'け' => "ke",
+        'こ' => "ko",
+        'が' => "ga",
+        'ぎ' => "gi",
+        'ぐ' => "gu",
+        'げ' => "ge",
+        'ご' => "go",
+        'さ' => "sa",
+        'し' => "shi",
+        'す' => "su",
+        'せ' => "se",
+        'そ' => "so",
+        'ざ' => "za",
+        'じ' => "ji",
+        'ず' => "zu",
+        'ぜ' => "ze",
+        'ぞ' => "zo",
+        'た' => "ta",
+        'ち' => "chi",
+        'つ' => "tsu",
+        'て' => "te",
+        'と' => "to",
+        'だ' => "da",
+        'ぢ' => "ji",
+        'づ' => "zu",
+        'で' => "de",
+        'ど' => "do",
+        'な' => "na",
+        'に' => "ni",
+        'ぬ' => "nu",
+        'ね' => "ne",
+        'の' => "no",
+        'は' => "ha",
+        'ひ' => "hi",
+        'ふ' => "fu",
+        'へ' => "he",
+        'ほ' => "ho",
+        'ば' => "ba",
+        'び' => "bi",
+        'ぶ' => "bu",
+        'べ' => "be",
+        'ぼ' => "bo",
+        'ぱ' => "pa",
+        'ぴ' => "pi",
+        'ぷ' => "pu",
+        'ぺ' => "pe",
+        'ぽ' => "po",
+        'ま' => "ma",
+        'み' => "mi",
+        'む' => "mu",
+        'め' => "me",
+        'も' => "mo",
+        'や' => "ya",
+        'ゆ' => "yu",
+        'よ' => "yo",
+        'ら' => "ra",
+        'り' => "ri",
+        'る' => "ru",
+        'れ' => "re",
+        'ろ' => "ro",
+        'わ' => "wa",
+        'ゐ' => "i",
+        'ゑ' => "e",
+        'を' => "wo",
+        'ん' => "n",
+        'ゔ' => "vu",
+        // Small kana on their own
+        'ぁ' => "a",
+        'ぃ' => "i",
+        'ぅ' => "u",
+        'ぇ' => "e",
+        'ぉ' => "o",
+        'ゃ' => "ya",
+        'ゅ' => "yu",
+        'ょ' => "yo",
+        'ゎ' => "wa",
+        _ => return None,
+    };
+
+    Some(romaji)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_spacing_and_punctuation() {
+        assert_eq!(to_romaji("あいかつ! あいどるかつどう!"), "aikatsu! aidorukatsudou!");
+        assert_eq!(to_romaji("けいおん!!"), "keion!!");
+    }
+
+    #[test]
+    fn transliterates_katakana() {
+        assert_eq!(to_romaji("アイカツ"), "aikatsu");
+        assert_eq!(to_romaji("ソードアート・オンライン"), "soodoaato・onrain");
+        assert_eq!(to_romaji("ヴァイオレット"), "vaioretto");
+    }
+
+    #[test]
+    fn doubles_consonants_after_small_tsu() {
+        assert_eq!(to_romaji("がっこう"), "gakkou");
+        assert_eq!(to_romaji("まっちゃ"), "matcha");
+        assert_eq!(to_romaji("ハッピー"), "happii");
+        // Nothing to double at the end
+        assert_eq!(to_romaji("あっ"), "a");
+    }
+
+    #[test]
+    fn spells_out_long_vowels() {
+        assert_eq!(to_romaji("ラーメン"), "raamen");
+        assert_eq!(to_romaji("コーヒー"), "koohii");
+        assert_eq!(to_romaji("とうきょう"), "toukyou");
+        assert_eq!(to_romaji("ティー"), "tii");
+    }
+
+    #[test]
+    fn separates_n_before_vowels() {
+        assert_eq!(to_romaji("れんあい"), "ren'ai");
+        assert_eq!(to_romaji("こんや"), "kon'ya");
+        assert_eq!(to_romaji("しんぶん"), "shinbun");
+    }
+
+    #[test]
+    fn passes_through_other_characters() {
+        assert_eq!(to_romaji("進撃の巨人"), "進撃no巨人");
+        assert_eq!(to_romaji("をとめ"), "wotome");
+        assert!(has_kana("進撃の巨人"));
+        assert!(!has_kana("進撃巨人"));
+    }
+
+    #[test]
+    fn normalizes_around_transliteration() {
+        assert_eq!(normalized_romaji("あいかつ! あいどるかつどう!"),
+                   "aikatsu! aidorukatsudou!");
+        assert_eq!(normalized_romaji("ｱｲｶﾂ!　ｱｲﾄﾞﾙｶﾂﾄﾞｳ!"), "aikatsu! aidorukatsudou!");
+        assert_eq!(normalized_romaji("ソードアート・オンライン"), "soodoaato onrain");
+    }
+}
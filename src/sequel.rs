@@ -0,0 +1,459 @@
+use elastic::Series;
+use error::*;
+use matcher::{Candidate, Matcher};
+use normalize::normalize;
+use std::cmp::Ordering;
+
+/// Season and sequel markers found in a title, e.g. "第2期", "Season 2",
+/// "2nd Season", "劇場版" or "OVA".
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Markers {
+    /// Season numbers, from "第2期", "Season 2", "2nd", "シーズン2", etc
+    pub seasons: Vec<u32>,
+    /// "劇場版", "映画", "Movie" or "Gekijouban"
+    pub movie: bool,
+    /// "OVA" or "OAD"
+    pub ova: bool,
+}
+
+// Multipliers applied to a candidate's score for each marker that agrees or
+// disagrees with the ClubDAM title
+const MARKER_MATCH_FACTOR: f64 = 1.5;
+const MARKER_MISMATCH_FACTOR: f64 = 0.5;
+
+impl Markers {
+    pub fn from_title(title: &str) -> Self {
+        let normalized = normalize(title);
+        let mut markers = Markers::default();
+
+        markers.add_word_markers(&normalized);
+        markers.add_cjk_markers(&normalized);
+
+        markers
+    }
+
+    /// Markers of the series' title that's most similar to `title`. Only one
+    /// title is used, since markers in other titles can belong to something
+    /// else entirely (e.g. a movie's synonym naming the TV series it follows).
+    pub fn from_series(series: &Series, title: &str) -> Self {
+        let normalized = normalize(title);
+
+        let best = series.titles
+            .0
+            .values()
+            .flat_map(|titles| titles)
+            .map(|t| (similarity(&normalized, &normalize(t)), t))
+            .fold(None, |best: Option<(f64, &String)>, (score, t)| {
+                match best {
+                    Some((best_score, _)) if best_score >= score => best,
+                    _ => Some((score, t)),
+                }
+            });
+
+        best.map_or_else(Markers::default, |(_, t)| Markers::from_title(t))
+    }
+
+    /// How much to scale the score of a candidate with the given markers, if
+    /// `self` are the markers of the title being matched. A title without a
+    /// season marker is treated as the first season, so e.g. "進撃の巨人"
+    /// prefers the first season over "進撃の巨人 Season2".
+    pub fn score_factor(&self, candidate: &Markers) -> f64 {
+        let mut factor = 1.0;
+
+        let season = self.seasons.first().cloned().unwrap_or(1);
+        let candidate_has_season = if candidate.seasons.is_empty() {
+            season == 1
+        } else {
+            candidate.seasons.contains(&season)
+        };
+
+        if !candidate_has_season {
+            factor *= MARKER_MISMATCH_FACTOR;
+        } else if season > 1 {
+            factor *= MARKER_MATCH_FACTOR;
+        }
+
+        for &(title_has, candidate_has) in &[(self.movie, candidate.movie),
+                                             (self.ova, candidate.ova)] {
+            if title_has != candidate_has {
+                factor *= MARKER_MISMATCH_FACTOR;
+            } else if title_has {
+                factor *= MARKER_MATCH_FACTOR;
+            }
+        }
+
+        factor
+    }
+
+    fn add_season(&mut self, season: u32) {
+        if !self.seasons.contains(&season) {
+            self.seasons.push(season);
+        }
+    }
+
+    // Markers in ASCII words, e.g. "season 2", "season2", "2nd", "ova", and
+    // romanized ones from x-jat titles like "dai 2 ki" and "gekijouban"
+    fn add_word_markers(&mut self, s: &str) {
+        let words = s.split(|c: char| !(c.is_ascii() && c.is_alphanumeric()))
+            .filter(|w| !w.is_empty())
+            .collect::<Vec<_>>();
+
+        for (i, &word) in words.iter().enumerate() {
+            let next = words.get(i + 1).cloned().unwrap_or("");
+
+            if word.starts_with("season") {
+                let number = if word.len() > "season".len() {
+                    &word["season".len()..]
+                } else {
+                    next
+                };
+
+                if let Ok(season) = number.parse::<u32>() {
+                    self.add_season(season);
+                }
+            } else if word == "dai" && words.get(i + 2) == Some(&"ki") {
+                if let Ok(season) = next.parse::<u32>() {
+                    self.add_season(season);
+                }
+            } else if let Some(season) = parse_ordinal(word) {
+                self.add_season(season);
+            } else if i == words.len() - 1 && s.ends_with(word) {
+                // Trailing roman numerals, e.g. "とある魔術の禁書目録ii"
+                let season = match word {
+                    "ii" => Some(2),
+                    "iii" => Some(3),
+                    "iv" => Some(4),
+                    _ => None,
+                };
+
+                if let Some(season) = season {
+                    self.add_season(season);
+                }
+            }
+
+            match word {
+                "movie" | "gekijouban" => self.movie = true,
+                "ova" | "ovas" | "oad" => self.ova = true,
+                _ => {}
+            }
+        }
+    }
+
+    // Markers in Japanese text, e.g. "第2期", "2期", "第二期", "シーズン2"
+    fn add_cjk_markers(&mut self, s: &str) {
+        let chars = s.chars().collect::<Vec<char>>();
+
+        for (i, &c) in chars.iter().enumerate() {
+            if c == '期' {
+                if let Some(season) = number_before(&chars[..i]) {
+                    self.add_season(season);
+                }
+            }
+        }
+
+        if let Some(pos) = s.find("シーズン") {
+            let after = &s[pos + "シーズン".len()..];
+            let digits = after.chars().take_while(|c| c.is_ascii() && c.is_digit(10));
+
+            if let Ok(season) = digits.collect::<String>().parse::<u32>() {
+                self.add_season(season);
+            }
+        }
+
+        if s.contains("劇場版") || s.contains("映画") {
+            self.movie = true;
+        }
+    }
+}
+
+// Dice coefficient of the two strings' character bigrams, from 0.0 (nothing
+// in common) to 1.0 (same bigrams)
+fn similarity(a: &str, b: &str) -> f64 {
+    let bigrams = |s: &str| {
+        let chars = s.chars().collect::<Vec<char>>();
+        chars.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>()
+    };
+
+    let a_bigrams = bigrams(a);
+    let mut b_bigrams = bigrams(b);
+
+    if a_bigrams.is_empty() || b_bigrams.is_empty() {
+        return if a == b { 1.0 } else { 0.0 };
+    }
+
+    let total = a_bigrams.len() + b_bigrams.len();
+    let mut common = 0;
+
+    for bigram in &a_bigrams {
+        if let Some(pos) = b_bigrams.iter().position(|b| b == bigram) {
+            b_bigrams.swap_remove(pos);
+            common += 1;
+        }
+    }
+
+    2.0 * common as f64 / total as f64
+}
+
+// Parses "1st", "2nd", "3rd", "4th", etc
+fn parse_ordinal(word: &str) -> Option<u32> {
+    let digits = word.chars().take_while(|c| c.is_digit(10)).count();
+    if digits == 0 {
+        return None;
+    }
+
+    match &word[digits..] {
+        "st" | "nd" | "rd" | "th" => word[..digits].parse::<u32>().ok(),
+        _ => None,
+    }
+}
+
+// Parses the number (ASCII digits or kanji numerals) at the end of `chars`
+fn number_before(chars: &[char]) -> Option<u32> {
+    let start = chars.iter()
+        .rposition(|&c| !(c.is_ascii() && c.is_digit(10)) && kanji_digit(c).is_none())
+        .map_or(0, |pos| pos + 1);
+
+    let number = &chars[start..];
+    if number.is_empty() {
+        return None;
+    }
+
+    if number.iter().all(|c| c.is_ascii()) {
+        number.iter().cloned().collect::<String>().parse::<u32>().ok()
+    } else {
+        parse_kanji_number(number)
+    }
+}
+
+fn kanji_digit(c: char) -> Option<u32> {
+    let digit = match c {
+        '一' => 1,
+        '二' => 2,
+        '三' => 3,
+        '四' => 4,
+        '五' => 5,
+        '六' => 6,
+        '七' => 7,
+        '八' => 8,
+        '九' => 9,
+        '十' => 10,
+        _ => return None,
+    };
+
+    Some(digit)
+}
+
+// Parses kanji numerals up to 99, e.g. "二" (2), "十二" (12), "二十" (20)
+fn parse_kanji_number(chars: &[char]) -> Option<u32> {
+    let mut digits = Vec::with_capacity(chars.len());
+    for &c in chars {
+        match kanji_digit(c) {
+            Some(d) => digits.push(d),
+            None => return None,
+        }
+    }
+
+    match digits.iter().position(|&d| d == 10) {
+        None if digits.len() == 1 => Some(digits[0]),
+        Some(pos) if pos <= 1 && digits.len() - pos <= 2 => {
+            let tens = if pos == 1 { digits[0] } else { 1 };
+            let ones = digits.get(pos + 1).cloned().unwrap_or(0);
+
+            if tens < 10 && ones < 10 {
+                Some(tens * 10 + ones)
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Wraps a `Matcher`, reranking its candidates by how well their season and
+/// sequel markers agree with the title's. More candidates than requested are
+/// fetched from the inner matcher, so that e.g. the second season can still
+/// be found when the first season scores higher before reranking.
+///
+/// Reranking multiplies each candidate's score by `Markers::score_factor`, so
+/// the scores returned (and so what `MatchThresholds` are compared against)
+/// are the inner matcher's scores scaled by between 0.125 and 3.375. E.g. a
+/// candidate with the wrong season has its score halved, which can push it
+/// below `min_score`, and a boosted candidate widens its margin over the next.
+///
+/// Candidates boosted by agreeing markers get "sequel_markers" added to their
+/// matched fields.
+pub struct SequelMatcher<M> {
+    inner: M,
+}
+
+// How many times as many candidates to fetch from the inner matcher
+const CANDIDATE_POOL_FACTOR: usize = 5;
+
+impl<M> SequelMatcher<M>
+    where M: Matcher
+{
+    pub fn new(inner: M) -> Self {
+        SequelMatcher { inner: inner }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+}
+
+impl<M> Matcher for SequelMatcher<M>
+    where M: Matcher
+{
+    fn find_candidates(&self, titles: &[String], limit: usize) -> Result<Vec<Vec<Candidate>>> {
+        let results = self.inner.find_candidates(titles, limit * CANDIDATE_POOL_FACTOR)?;
+
+        let reranked = titles.iter()
+            .zip(results)
+            .map(|(title, candidates)| rerank(title, candidates, limit))
+            .collect();
+
+        Ok(reranked)
+    }
+}
+
+fn rerank(title: &str, mut candidates: Vec<Candidate>, limit: usize) -> Vec<Candidate> {
+    let title_markers = Markers::from_title(title);
+
+    for candidate in &mut candidates {
+        let factor = title_markers.score_factor(&Markers::from_series(&candidate.series, title));
+        candidate.score *= factor;
+
+        if factor > 1.0 {
+            candidate.matched_fields.push("sequel_markers".to_string());
+        }
+    }
+
+    // Stable, so candidates with equal scores keep the inner matcher's order
+    candidates.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal));
+    candidates.truncate(limit);
+    candidates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use elastic::TitlesByLanguage;
+    use std::collections::HashMap;
+
+    fn series(id: &str, titles: &[(&str, &str)]) -> Series {
+        let mut by_language = HashMap::new();
+        for &(language, title) in titles {
+            by_language.entry(language.to_string())
+                .or_insert_with(Vec::new)
+                .push(title.to_string());
+        }
+
+        Series {
+            id: id.to_string(),
+            main_title: None,
+            titles: TitlesByLanguage(by_language),
+            normalized_titles: Vec::new(),
+        }
+    }
+
+    fn candidate(series: Series, score: f64) -> Candidate {
+        Candidate {
+            series: series,
+            score: score,
+            matched_fields: Vec::new(),
+        }
+    }
+
+    fn seasons(title: &str) -> Vec<u32> {
+        Markers::from_title(title).seasons
+    }
+
+    #[test]
+    fn finds_season_markers_in_real_titles() {
+        assert_eq!(seasons("進撃の巨人 Season2"), vec![2]);
+        assert_eq!(seasons("Shingeki no Kyojin Season 2"), vec![2]);
+        assert_eq!(seasons("ゆるキャン△ SEASON2"), vec![2]);
+        assert_eq!(seasons("黒子のバスケ 第2期"), vec![2]);
+        assert_eq!(seasons("Kuroko no Basket 2nd Season"), vec![2]);
+        assert_eq!(seasons("暗殺教室 第二期"), vec![2]);
+        assert_eq!(seasons("とある魔術の禁書目録II"), vec![2]);
+    }
+
+    #[test]
+    fn ignores_numbers_that_arent_seasons() {
+        assert_eq!(seasons("3月のライオン"), Vec::<u32>::new());
+        assert_eq!(seasons("Ansatsu Kyoushitsu (2016)"), Vec::<u32>::new());
+        assert_eq!(seasons("うたの☆プリンスさまっ♪ マジLOVE2000%"), Vec::<u32>::new());
+        assert_eq!(Markers::from_title("ハイキュー!! 烏野高校 VS 白鳥沢学園高校"),
+                   Markers::default());
+    }
+
+    #[test]
+    fn finds_movie_and_ova_markers_in_real_titles() {
+        assert!(Markers::from_title("劇場版 魔法少女まどか☆マギカ [新編] 叛逆の物語").movie);
+        assert!(Markers::from_title("Gekijouban Mahou Shoujo Madoka Magica: Shinpen - Hangyaku \
+                                     no Monogatari")
+            .movie);
+        assert!(Markers::from_title("ラブライブ! The School Idol Movie").movie);
+        assert!(Markers::from_title("ARIA The OVA ~ARIETTA~").ova);
+        assert!(!Markers::from_title("魔法少女まどか☆マギカ").movie);
+    }
+
+    #[test]
+    fn uses_markers_of_most_similar_title_only() {
+        let aikatsu = series("9355",
+                             &[("ja", "アイカツ! アイドルカツドウ!"),
+                               ("x-jat", "Aikatsu! Idol Katsudou!"),
+                               ("en", "Aikatsu! The Movie")]);
+
+        assert_eq!(Markers::from_series(&aikatsu, "アイカツ! アイドルカツドウ!"),
+                   Markers::default());
+        assert!(Markers::from_series(&aikatsu, "Aikatsu! The Movie").movie);
+    }
+
+    #[test]
+    fn prefers_matching_season() {
+        let first = series("9541", &[("ja", "進撃の巨人"), ("x-jat", "Shingeki no Kyojin")]);
+        let second = series("12345",
+                            &[("ja", "進撃の巨人 Season2"),
+                              ("x-jat", "Shingeki no Kyojin Season 2")]);
+
+        let candidates = vec![candidate(first.clone(), 10.0), candidate(second.clone(), 8.0)];
+        let reranked = rerank("進撃の巨人 Season2", candidates, 2);
+
+        assert_eq!(reranked[0].series.id, "12345");
+        assert_eq!(reranked[0].score, 12.0);
+        assert_eq!(reranked[0].matched_fields, vec!["sequel_markers".to_string()]);
+        assert_eq!(reranked[1].series.id, "9541");
+        assert_eq!(reranked[1].score, 5.0);
+
+        // Without a season marker, the first season is preferred
+        let candidates = vec![candidate(second, 10.0), candidate(first, 8.0)];
+        let reranked = rerank("進撃の巨人", candidates, 1);
+
+        assert_eq!(reranked.len(), 1);
+        assert_eq!(reranked[0].series.id, "9541");
+        assert_eq!(reranked[0].score, 8.0);
+    }
+
+    #[test]
+    fn prefers_movie_for_movie_titles() {
+        let tv = series("8069",
+                        &[("ja", "魔法少女まどか☆マギカ"), ("x-jat", "Mahou Shoujo Madoka Magica")]);
+        let movie = series("9914",
+                           &[("ja", "劇場版 魔法少女まどか☆マギカ [新編] 叛逆の物語"),
+                             ("x-jat",
+                              "Gekijouban Mahou Shoujo Madoka Magica: Shinpen - Hangyaku no \
+                               Monogatari")]);
+
+        let candidates = vec![candidate(tv, 10.0), candidate(movie, 9.0)];
+        let reranked = rerank("劇場版 魔法少女まどか☆マギカ [新編] 叛逆の物語", candidates, 2);
+
+        assert_eq!(reranked[0].series.id, "9914");
+        assert_eq!(reranked[1].series.id, "8069");
+    }
+}
@@ -0,0 +1,183 @@
+use {DumpMetadata, Title};
+use entities;
+use error::*;
+use std::borrow::Cow;
+use std::io::Write;
+use time;
+
+/// Writes titles in the same `id|type|language|title` format as AniDB's
+/// `.dat` dump, so that reading the output with `TitleIterator` gives back
+/// the same titles.
+///
+/// Titles and header comments read from a `.dat` dump are written exactly as
+/// they appeared, so writing out a parsed dump reproduces it byte for byte.
+/// Other titles (e.g. from the XML dump, or ones that were changed after
+/// parsing) are escaped with `entities::encode`.
+pub struct TitleWriter<W> {
+    writer: W,
+}
+
+impl<W> TitleWriter<W>
+    where W: Write
+{
+    /// Creates a writer, immediately writing the header comments from
+    /// `metadata`. If there are none (e.g. the titles came from the XML dump),
+    /// a header in AniDB's usual format is written instead.
+    pub fn new(mut writer: W, metadata: &DumpMetadata) -> Result<Self> {
+        if !metadata.raw_comments.is_empty() {
+            for comment in &metadata.raw_comments {
+                writeln!(writer, "{}", comment)?;
+            }
+        } else if metadata.comments.is_empty() {
+            let created = metadata.created.unwrap_or_else(time::now_utc);
+
+            writeln!(writer, "# created: {}", created.strftime("%a %b %e %H:%M:%S %Y").unwrap())?;
+            writeln!(writer, "# <aid>|<type>|<language>|<title>")?;
+            writeln!(writer,
+                     "# type: 1=primary title (one per anime), 2=synonyms (multiple per anime), \
+                      3=shorttitles (multiple per anime), 4=official title (one per language)")?;
+        } else {
+            for comment in &metadata.comments {
+                writeln!(writer, "# {}", comment)?;
+            }
+        }
+
+        Ok(TitleWriter { writer: writer })
+    }
+
+    pub fn write(&mut self, title: &Title) -> Result<()> {
+        let type_id = match title.title_type.id() {
+            Some(id) => id,
+            None => {
+                let name = title.title_type.name().to_string();
+                return Err(ErrorKind::UnwritableTitleType(name).into());
+            }
+        };
+
+        // The raw title is only used if it still matches, in case the title
+        // was changed after parsing
+        let text = match title.raw_title {
+            Some(ref raw) if entities::decode(raw) == title.title => Cow::Borrowed(raw.as_str()),
+            _ => entities::encode(&title.title),
+        };
+
+        writeln!(self.writer,
+                 "{}|{}|{}|{}",
+                 title.id,
+                 type_id,
+                 title.language,
+                 text)?;
+
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {Title, TitleIterator, TitleType};
+    use std::io::Cursor;
+
+    // Lines from anime-titles.dat, including entities that AniDB writes
+    // inconsistently (e.g. both "&#039;" and a raw "'")
+    const DUMP: &'static str = "# created: Fri Mar 10 02:00:01 2017
+# <aid>|<type>|<language>|<title>
+# type: 1=primary title (one per anime), 2=synonyms (multiple per anime), 3=shorttitles \
+(multiple per anime), 4=official title (one per language)
+1|1|x-jat|Seikai no Monshou
+1|4|ja|星界の紋章
+1|4|en|Crest of the Stars
+71|1|x-jat|Hitsuji no Uta
+71|4|en|Sheep&#039;s Song
+193|4|en|Tom &amp; Jerry&#x27;s &quot;Giant&quot; Adventure
+193|2|en|Tom and Jerry's \"Giant\" Adventure
+1530|1|x-jat|Dragon Ball Z Movie 1: Ora no Gohan o Kaese!!
+1530|2|x-jat|DBZ&hellip;&nbsp;Kaese
+5101|2|en|Kidou Senshi Gundam Seed C.E.73: Stargazer <Special>
+5101|3|en|&lt;Special&gt; & more
+9999|1|x-jat|Shin Evangelion Gekijouban:||
+";
+
+    fn round_trip(input: &'static str) -> String {
+        let mut titles = TitleIterator::from_reader(Cursor::new(input.as_bytes())).unwrap();
+        let metadata = titles.metadata().clone();
+        let mut writer = TitleWriter::new(Vec::new(), &metadata).unwrap();
+
+        for title in titles.by_ref() {
+            writer.write(&title.unwrap()).unwrap();
+        }
+
+        String::from_utf8(writer.into_inner()).unwrap()
+    }
+
+    #[test]
+    fn writes_parsed_dump_back_unchanged() {
+        assert_eq!(round_trip(DUMP), DUMP);
+    }
+
+    #[test]
+    fn keeps_header_comments_unchanged() {
+        let input = "#created: Fri Mar 10 02:00:01 2017  \n#\n# <aid>|<type>|<language>|<title>\n";
+        assert_eq!(round_trip(input), input);
+    }
+
+    #[test]
+    fn escapes_changed_titles() {
+        let mut titles = TitleIterator::from_reader(Cursor::new(DUMP.as_bytes())).unwrap();
+        let mut title = titles.nth(4).unwrap().unwrap();
+        assert_eq!(title.title, "Sheep's Song");
+
+        title.title = "Sheep's Song & Dance".to_string();
+
+        let mut writer = TitleWriter::new(Vec::new(), titles.metadata()).unwrap();
+        writer.write(&title).unwrap();
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        assert!(output.ends_with("71|4|en|Sheep&#039;s Song &amp; Dance\n"));
+    }
+
+    #[test]
+    fn writes_default_header_and_escapes_xml_titles() {
+        let metadata = DumpMetadata::default();
+        let mut writer = TitleWriter::new(Vec::new(), &metadata).unwrap();
+
+        writer.write(&Title {
+                id: 71,
+                title_type: TitleType::Official,
+                language: "en".to_string(),
+                title: "Sheep's Song".to_string(),
+                raw_title: None,
+            })
+            .unwrap();
+
+        let output = String::from_utf8(writer.into_inner()).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[0].starts_with("# created: "));
+        assert_eq!(lines[1], "# <aid>|<type>|<language>|<title>");
+        assert_eq!(lines[3], "71|4|en|Sheep&#039;s Song");
+
+        let mut parsed = TitleIterator::from_reader(Cursor::new(output.into_bytes())).unwrap();
+        assert_eq!(parsed.next().unwrap().unwrap().title, "Sheep's Song");
+    }
+
+    #[test]
+    fn rejects_types_without_dat_ids() {
+        let mut writer = TitleWriter::new(Vec::new(), &DumpMetadata::default()).unwrap();
+
+        let result = writer.write(&Title {
+            id: 1,
+            title_type: TitleType::Kana,
+            language: "ja".to_string(),
+            title: "せいかいのもんしょう".to_string(),
+            raw_title: None,
+        });
+
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,358 @@
+use {Title, TitleType, decompress};
+use error::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use xml_rs::common::Position;
+use xml_rs::reader::{EventReader, XmlEvent};
+
+/// Iterates over titles in AniDB's XML dump (`anime-titles.xml`), yielding
+/// the same `Title`s as `TitleIterator` does for the `.dat` dump.
+///
+/// The XML looks like:
+///
+/// ```xml
+/// <animetitles>
+///   <anime aid="1">
+///     <title xml:lang="x-jat" type="main">Seikai no Monshou</title>
+///     <title xml:lang="ja" type="official">星界の紋章</title>
+///   </anime>
+/// </animetitles>
+/// ```
+pub struct XmlTitleIterator {
+    reader: EventReader<Box<Read>>,
+    anime_id: Option<u32>,
+    current: Option<TitleAttributes>,
+    done: bool,
+}
+
+struct TitleAttributes {
+    title_type: Option<String>,
+    language: Option<String>,
+    text: String,
+    line_num: u32,
+}
+
+impl TitleAttributes {
+    // The element as it (roughly) appeared in the dump, for error reporting
+    fn to_raw(&self) -> String {
+        let mut raw = "<title".to_string();
+
+        if let Some(ref language) = self.language {
+            raw.push_str(&format!(" xml:lang=\"{}\"", language));
+        }
+        if let Some(ref title_type) = self.title_type {
+            raw.push_str(&format!(" type=\"{}\"", title_type));
+        }
+
+        raw.push_str(&format!(">{}</title>", self.text));
+        raw
+    }
+}
+
+impl XmlTitleIterator {
+    /// Opens an AniDB XML titles dump, which may be gzipped.
+    pub fn new<P>(file_path: P) -> Result<XmlTitleIterator>
+        where P: AsRef<Path>
+    {
+        let file = File::open(file_path)?;
+        XmlTitleIterator::from_reader(file)
+    }
+
+    pub fn from_reader<R>(source: R) -> Result<XmlTitleIterator>
+        where R: Read + 'static
+    {
+        Ok(XmlTitleIterator {
+            reader: EventReader::new(decompress(source)?),
+            anime_id: None,
+            current: None,
+            done: false,
+        })
+    }
+
+    fn line_num(&self) -> u32 {
+        self.reader.position().row as u32 + 1
+    }
+
+    // Fails on a single bad element, wrapped in `InvalidLine` like the `.dat`
+    // parser's errors so that lenient mode can skip it
+    fn fail_line<T>(&self, kind: ErrorKind, line_num: u32, raw: String) -> Option<Result<T>> {
+        Some(Err(Error::from(kind)).chain_err(|| ErrorKind::InvalidLine(line_num, raw)))
+    }
+}
+
+impl Iterator for XmlTitleIterator {
+    type Item = Result<Title>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        loop {
+            let event = match self.reader.next() {
+                Ok(event) => event,
+                Err(e) => {
+                    // The reader keeps returning the same error once it fails
+                    self.done = true;
+                    return Some(Err(e.into()));
+                }
+            };
+
+            match event {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    match name.local_name.as_str() {
+                        "anime" => {
+                            // Titles of an anime without a valid ID fail too
+                            self.anime_id = None;
+
+                            let line_num = self.line_num();
+                            let aid = attributes.into_iter()
+                                .find(|a| a.name.local_name == "aid")
+                                .map(|a| a.value);
+
+                            let aid = match aid {
+                                Some(aid) => aid,
+                                None => {
+                                    let kind = ErrorKind::InvalidParse(line_num);
+                                    return self.fail_line(kind, line_num, "<anime>".to_string());
+                                }
+                            };
+
+                            match aid.parse::<u32>() {
+                                Ok(id) => self.anime_id = Some(id),
+                                Err(_) => {
+                                    let raw = format!("<anime aid=\"{}\">", aid);
+                                    let kind = ErrorKind::InvalidId(aid, line_num);
+                                    return self.fail_line(kind, line_num, raw);
+                                }
+                            }
+                        }
+                        "title" => {
+                            let mut title_type = None;
+                            let mut language = None;
+
+                            for attr in attributes {
+                                match (attr.name.prefix.as_ref().map(|p| p.as_str()),
+                                       attr.name.local_name.as_str()) {
+                                    (Some("xml"), "lang") => language = Some(attr.value),
+                                    (None, "type") => title_type = Some(attr.value),
+                                    _ => {}
+                                }
+                            }
+
+                            // Missing attributes are reported at the end of the
+                            // element, so the error includes the title's text
+                            self.current = Some(TitleAttributes {
+                                title_type: title_type,
+                                language: language,
+                                text: String::new(),
+                                line_num: self.line_num(),
+                            });
+                        }
+                        _ => {}
+                    }
+                }
+
+                XmlEvent::Characters(s) |
+                XmlEvent::CData(s) => {
+                    if let Some(ref mut current) = self.current {
+                        current.text.push_str(&s);
+                    }
+                }
+
+                XmlEvent::EndElement { name } => {
+                    match name.local_name.as_str() {
+                        "anime" => self.anime_id = None,
+                        "title" => {
+                            let current = match self.current.take() {
+                                Some(current) => current,
+                                None => {
+                                    // Can't happen with well-formed XML
+                                    self.done = true;
+                                    return Some(Err(ErrorKind::InvalidParse(self.line_num())
+                                        .into()));
+                                }
+                            };
+
+                            let line_num = current.line_num;
+                            let raw = current.to_raw();
+
+                            let (id, title_type, language) =
+                                match (self.anime_id, current.title_type, current.language) {
+                                    (Some(id), Some(title_type), Some(language)) => {
+                                        (id, title_type, language)
+                                    }
+                                    _ => {
+                                        let kind = ErrorKind::InvalidParse(line_num);
+                                        return self.fail_line(kind, line_num, raw);
+                                    }
+                                };
+
+                            return Some(Ok(Title {
+                                id: id,
+                                title_type: TitleType::from_xml_name(&title_type),
+                                language: language,
+                                title: current.text,
+                                raw_title: None,
+                            }));
+                        }
+                        _ => {}
+                    }
+                }
+
+                XmlEvent::EndDocument => {
+                    self.done = true;
+                    return None;
+                }
+
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn parse(input: &'static str) -> Vec<Result<Title>> {
+        XmlTitleIterator::from_reader(Cursor::new(input.as_bytes())).unwrap().collect()
+    }
+
+    #[test]
+    fn parses_each_title_type() {
+        let input = r#"<?xml version="1.0" encoding="UTF-8"?>
+<animetitles>
+  <anime aid="1">
+    <title xml:lang="x-jat" type="main">Seikai no Monshou</title>
+    <title xml:lang="en" type="syn">Crest of the Stars</title>
+    <title xml:lang="x-jat" type="short">SnM</title>
+    <title xml:lang="ja" type="official">星界の紋章</title>
+    <title xml:lang="ja" type="kana">せいかいのもんしょう</title>
+    <title xml:lang="ja" type="card">星界ノ紋章</title>
+    <title xml:lang="en" type="unheard">Something New</title>
+  </anime>
+</animetitles>"#;
+
+        let titles = parse(input).into_iter().collect::<Result<Vec<_>>>().unwrap();
+        let types = titles.iter().map(|t| t.title_type.clone()).collect::<Vec<_>>();
+
+        assert_eq!(types,
+                   vec![TitleType::Primary,
+                        TitleType::Synonym,
+                        TitleType::Short,
+                        TitleType::Official,
+                        TitleType::Kana,
+                        TitleType::Card,
+                        TitleType::Unknown("unheard".to_string())]);
+
+        assert!(titles.iter().all(|t| t.id == 1));
+        assert_eq!(titles[4].language, "ja");
+        assert_eq!(titles[4].title, "せいかいのもんしょう");
+        assert_eq!(titles[5].title, "星界ノ紋章");
+    }
+
+    #[test]
+    fn assigns_titles_to_their_anime() {
+        let input = r#"<animetitles>
+  <anime aid="1"><title xml:lang="x-jat" type="main">Seikai no Monshou</title></anime>
+  <anime aid="22"><title xml:lang="x-jat" type="main">Shinseiki Evangelion</title></anime>
+</animetitles>"#;
+
+        let ids = parse(input).into_iter().map(|t| t.unwrap().id).collect::<Vec<_>>();
+        assert_eq!(ids, vec![1, 22]);
+    }
+
+    #[test]
+    fn parses_gzipped_input() {
+        use flate2::Compression;
+        use flate2::write::GzEncoder;
+        use std::io::Write;
+
+        let input = r#"<animetitles>
+  <anime aid="1"><title xml:lang="x-jat" type="main">Seikai no Monshou</title></anime>
+  <anime aid="22"><title xml:lang="ja" type="official">新世紀エヴァンゲリオン</title></anime>
+</animetitles>"#;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+        encoder.write_all(input.as_bytes()).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let titles = XmlTitleIterator::from_reader(Cursor::new(gzipped))
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+
+        assert_eq!(titles, parse(input).into_iter().collect::<Result<Vec<_>>>().unwrap());
+        assert_eq!(titles[1].title, "新世紀エヴァンゲリオン");
+    }
+
+    #[test]
+    fn decodes_xml_entities() {
+        let input = r#"<animetitles><anime aid="1">
+    <title xml:lang="en" type="syn">Tom &amp; Jerry &quot;Kids&quot;</title>
+</anime></animetitles>"#;
+
+        let titles = parse(input);
+        assert_eq!(titles[0].as_ref().unwrap().title, "Tom & Jerry \"Kids\"");
+    }
+
+    #[test]
+    fn bad_elements_are_invalid_lines() {
+        let input = r#"<animetitles>
+<anime aid="abc">
+<title xml:lang="x-jat" type="main">Bad ID</title>
+</anime>
+<anime aid="2">
+<title type="main">No Language</title>
+<title xml:lang="x-jat" type="main">Seikai no Senki</title>
+</anime>
+</animetitles>"#;
+
+        let results = parse(input);
+        assert_eq!(results.len(), 4);
+
+        let lines = results[..3]
+            .iter()
+            .map(|r| match *r.as_ref().unwrap_err().kind() {
+                ErrorKind::InvalidLine(line_num, ref raw) => (line_num, raw.clone()),
+                ref kind => panic!("unexpected error: {}", kind),
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(lines,
+                   vec![(2, "<anime aid=\"abc\">".to_string()),
+                        (3, "<title xml:lang=\"x-jat\" type=\"main\">Bad ID</title>".to_string()),
+                        (6, "<title type=\"main\">No Language</title>".to_string())]);
+
+        assert_eq!(results[3].as_ref().unwrap().title, "Seikai no Senki");
+    }
+
+    #[test]
+    fn lenient_mode_skips_bad_elements() {
+        use parse_mode::{BadLineReason, CheckedTitles, ParseMode};
+
+        let input = r#"<animetitles>
+<anime aid="abc"><title xml:lang="x-jat" type="main">Bad ID</title></anime>
+<anime aid="2"><title xml:lang="x-jat" type="main">Seikai no Senki</title></anime>
+</animetitles>"#;
+
+        let iter = XmlTitleIterator::from_reader(Cursor::new(input.as_bytes())).unwrap();
+        let mut checked = CheckedTitles::new(iter, ParseMode::Lenient { max_bad_ratio: 1.0 });
+        let titles = checked.by_ref().collect::<Result<Vec<_>>>().unwrap();
+
+        assert_eq!(titles.len(), 1);
+        assert_eq!(titles[0].id, 2);
+
+        // The title fails too, since its anime has no valid ID
+        let reasons = checked.report()
+            .bad_lines
+            .iter()
+            .map(|b| b.reason.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(reasons,
+                   vec![BadLineReason::InvalidId("abc".to_string()), BadLineReason::InvalidParse]);
+    }
+}